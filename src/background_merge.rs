@@ -0,0 +1,166 @@
+// src/background_merge.rs
+
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::bf_tree::BfTree;
+use crate::config::BackgroundMergeOptions;
+use crate::error::{Error, Result};
+
+/// A background thread that merges dirty mini-pages before writers have to
+/// (see BfTree::background_merge), so inserts and deletes only merge or
+/// evict themselves when the worker falls behind and memory runs out.
+///
+/// Every interval the worker runs one pass. Its I/O budget refills at
+/// options.io_budget bytes per second, up to one second's worth, so idle
+/// periods do not turn into bursts. A writer that finds its mini-page at
+/// mini_page_max_size wakes the worker and waits for it to merge the page,
+/// for at most one interval before merging it itself.
+///
+/// The worker holds the tree weakly and stops once the tree is dropped;
+/// stop it before closing the tree. A pass that fails stops the worker, and
+/// stop returns the error.
+pub struct BackgroundMerger {
+    tree: Weak<BfTree>,
+    signal: Arc<MergeSignal>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+/// Shared by a BackgroundMerger and its tree: writers queue the pages whose
+/// mini-page cannot grow, and the worker merges them ahead of its pass.
+pub(crate) struct MergeSignal {
+    state: Mutex<SignalState>,
+    wake: Condvar,
+    interval: Duration, // the worker's pause between passes, and a writer's longest wait
+}
+
+#[derive(Default)]
+struct SignalState {
+    stopped: bool,
+    full_pages: Vec<usize>, // page IDs writers wait on, until merged
+}
+
+impl MergeSignal {
+    /// Queues page_id for the worker and waits until it is merged.
+    /// Returns false if the worker stopped or did not get to the page in
+    /// time; the page is no longer queued then, and the writer merges it.
+    pub(crate) fn wait_for_merge(&self, page_id: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return false;
+        }
+        if !state.full_pages.contains(&page_id) {
+            state.full_pages.push(page_id);
+            self.wake.notify_all();
+        }
+        let mut state = self
+            .wake
+            .wait_timeout_while(state, self.interval, |state| !state.stopped && state.full_pages.contains(&page_id))
+            .unwrap()
+            .0;
+        match state.full_pages.iter().position(|&queued| queued == page_id) {
+            Some(i) => {
+                state.full_pages.remove(i);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// The page IDs writers are waiting on.
+    pub(crate) fn full_pages(&self) -> Vec<usize> {
+        self.state.lock().unwrap().full_pages.clone()
+    }
+
+    /// Wakes the writers waiting on page_ids, which were merged.
+    pub(crate) fn merged(&self, page_ids: &[usize]) {
+        self.state.lock().unwrap().full_pages.retain(|page_id| !page_ids.contains(page_id));
+        self.wake.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+        self.wake.notify_all();
+    }
+}
+
+impl BackgroundMerger {
+    /// Starts the worker for tree. Fails with Error::InvalidOptions if options do not validate.
+    pub fn start(tree: &Arc<BfTree>, options: BackgroundMergeOptions) -> Result<Self> {
+        options.validate()?;
+        let signal = Arc::new(MergeSignal {
+            state: Mutex::new(SignalState::default()),
+            wake: Condvar::new(),
+            interval: options.interval,
+        });
+        *tree.merge_signal.lock().unwrap() = Some(Arc::clone(&signal));
+
+        let worker_tree = Arc::downgrade(tree);
+        let worker_signal = Arc::clone(&signal);
+        let handle = std::thread::spawn(move || {
+            let result = Self::run(worker_tree, options, &worker_signal);
+            // Writers stop waiting for a worker that is gone
+            worker_signal.stop();
+            result
+        });
+        Ok(Self { tree: Arc::downgrade(tree), signal, handle: Some(handle) })
+    }
+
+    fn run(tree: Weak<BfTree>, options: BackgroundMergeOptions, signal: &MergeSignal) -> Result<()> {
+        let mut budget = 0;
+        let mut refilled = Instant::now();
+        loop {
+            let state = signal
+                .wake
+                .wait_timeout_while(signal.state.lock().unwrap(), options.interval, |state| {
+                    !state.stopped && state.full_pages.is_empty()
+                })
+                .unwrap()
+                .0;
+            if state.stopped {
+                return Ok(());
+            }
+            drop(state);
+
+            let now = Instant::now();
+            let refill = (options.io_budget as f64 * (now - refilled).as_secs_f64()) as usize;
+            budget = (budget + refill).min(options.io_budget);
+            refilled = now;
+
+            let tree = match tree.upgrade() {
+                Some(tree) => tree,
+                None => return Ok(()),
+            };
+            budget = budget.saturating_sub(tree.background_merge(&options, budget)?);
+        }
+    }
+
+    /// Stops the worker and waits for its current pass to finish.
+    /// Returns the error that stopped it early, if any.
+    pub fn stop(mut self) -> Result<()> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> Result<()> {
+        self.signal.stop();
+        if let Some(tree) = self.tree.upgrade() {
+            let mut merge_signal = tree.merge_signal.lock().unwrap();
+            if merge_signal.as_ref().is_some_and(|signal| Arc::ptr_eq(signal, &self.signal)) {
+                *merge_signal = None;
+            }
+        }
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .unwrap_or_else(|_| Err(Error::InvalidState("background merge worker panicked".to_string()))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for BackgroundMerger {
+    fn drop(&mut self) {
+        let _ = self.shut_down();
+    }
+}
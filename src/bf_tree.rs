@@ -1,213 +1,926 @@
 // src/bf_tree.rs
 
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
+use crate::background_merge::MergeSignal;
+use crate::buffer_pool::BufferPool;
+use crate::checkpoint::Checkpoint;
+use crate::config::{BackgroundMergeOptions, BfTreeOptions};
+use crate::error::{Error, Result};
 use crate::mini_page::MiniPage;
 use crate::leaf_page::LeafPage;
-use crate::mapping_table::MappingTable;
-use crate::inner_node::InnerNode;
+use crate::mapping_table::{MappingTable, PageEntry};
+use crate::inner_node::{InnerNode, InnerTree};
+use crate::page::{Fences, RecordType};
 use crate::page_id_allocator::PageIdAllocator;
-use crate::page::RecordType;
+use crate::storage::PageFile;
+use crate::range_scan::RangeIter;
+use crate::wal::Wal;
 
+/// BfTree is Send + Sync: every operation takes &self, so one tree can be
+/// shared between threads (e.g. in an Arc).
+/// - Inner nodes are read with optimistic lock coupling (see InnerTree), so
+///   lookups never lock them; only splits latch the nodes they change.
+/// - Each leaf page has a latch bit in its MappingTable entry, guarding its mini-page.
+/// - The buffer pool is a Mutex held only for slot bookkeeping. The mini-pages are
+///   stored in its memory region, in slots owned by their page latch.
+/// - Memory lock-free readers may still hold (replaced inner node images, mini-page
+///   boxes of the MappingTable) is retired to an epoch Collector, not freed.
+///
+/// Lock order: page latch → inner node writers → buffer pool. No page latch is waited
+/// for while another one is held; a leaf merge only tries its neighbour's latch.
 pub struct BfTree {
-    pub mapping_table: MappingTable,
-    pub root_inner_node: InnerNode, 
-    pub inner_nodes: HashMap<u64, InnerNode>, 
+    pub(crate) mapping_table: MappingTable,
+    pub(crate) inner: InnerTree,
+    pub(crate) storage: PageFile,
+    pub(crate) buffer_pool: Mutex<BufferPool>,
+    pub(crate) page_id_allocator: PageIdAllocator,
+    pub(crate) wal: Wal,
+    pub(crate) options: BfTreeOptions,
+    structure_version: AtomicU64, // bumped whenever a leaf split or merge changes routing
+    quiesce: RwLock<()>,          // shared by writers, exclusive for checkpoints
+    checkpointing: AtomicBool,    // set while a writer takes a checkpoint past options.checkpoint_threshold
+    pub(crate) merge_signal: Mutex<Option<Arc<MergeSignal>>>, // set while a BackgroundMerger runs
+}
+
+/// Outcome of buffering a record under its page latch.
+enum Buffered {
+    /// The record is in the mini-page; carries its LSN if it was logged.
+    Done(Option<u64>),
+    /// The buffer pool needs this many free bytes first.
+    NeedSpace(usize),
+    /// The mini-page was merged and the leaf may have split; route again.
+    Reroute,
+    /// The mini-page cannot grow and a BackgroundMerger runs; wait for it to
+    /// merge this page ID, then route again.
+    Full(usize),
 }
 
 impl BfTree {
 
+    /// Creates a new, empty tree backed by the file at path.
+    /// The tree starts with a root InnerNode over a single empty leaf page,
+    /// recorded in an initial checkpoint.
+    /// The options' page and node sizes are persisted in the file header.
+    /// An empty write-ahead log is created next to it, at "<path>.wal".
+    pub fn create<P: AsRef<Path>>(path: P, options: BfTreeOptions) -> Result<Self> {
+        let storage = PageFile::create(&path, &options)?;
+        let wal = Wal::create(Wal::path_for(&path), options.wal_sync_policy)?;
+
+        let leaf_offset = storage.allocate_page();
+        LeafPage::with_size(storage.page_size()).flush_to_disk(&storage, leaf_offset)?;
+
+        // Page ID 0 is the root; the first leaf gets page ID 1
+        let inner = InnerTree::from_nodes([(0, InnerNode { keys: Vec::new(), children: vec![1] })]);
+        let buffer_pool = BufferPool::new(options.buffer_pool_size);
+        let mapping_table = MappingTable::new(2, Arc::clone(buffer_pool.memory()))?;
+        mapping_table.insert(1, None, leaf_offset)?;
+
+        let tree = Self::from_parts(storage, wal, options, inner, buffer_pool, mapping_table, PageIdAllocator::new(2));
+        tree.checkpoint()?;
+        Ok(tree)
+    }
+
+    /// Opens the tree stored in the file at path.
+    /// Page and node sizes come from the file header; only the runtime
+    /// settings (buffer pool size, caching probability, WAL sync policy, copy-on-write
+    /// flushes) are taken from options.
+    /// The inner nodes, mapping table, page ID allocator and free pages are
+    /// restored from the newest checkpoint; inserts and deletes logged
+    /// after it are then replayed into the mini-pages.
+    /// Fails with Error::Corruption if the newest checkpoint does not validate.
+    pub fn open<P: AsRef<Path>>(path: P, options: BfTreeOptions) -> Result<Self> {
+        let storage = PageFile::open(&path)?;
+        let options = storage.header().apply_to(&options);
+        options.validate()?;
+        let (wal, wal_records) = Wal::open(Wal::path_for(&path), options.wal_sync_policy)?;
+
+        // The older checkpoint slot is no fallback: the log was emptied past it,
+        // and leaves and pages it references may have been overwritten since
+        let pointer = storage.header().checkpoints_newest_first().into_iter().next()
+            .ok_or_else(|| Error::Corruption("file holds no checkpoint".to_string()))?;
+        let checkpoint = Checkpoint::read(&storage, &pointer)?;
+
+        // Pages allocated after the checkpoint was taken are free too. The
+        // checkpoint itself may occupy pages that were free when it was taken.
+        let checkpoint_pages: HashSet<u64> = pointer.pages(storage.page_size()).collect();
+        let written_since = (checkpoint.page_end..storage.allocated_end()).step_by(storage.page_size());
+        let free_pages = checkpoint.free_pages.iter().copied().chain(written_since);
+        storage.restore_free_pages(free_pages.filter(|offset| !checkpoint_pages.contains(offset)));
+
+        let inner = InnerTree::from_nodes(checkpoint.inner_nodes);
+        let buffer_pool = BufferPool::new(options.buffer_pool_size);
+        let mapping_table = MappingTable::new(checkpoint.next_page_id as usize, Arc::clone(buffer_pool.memory()))?;
+        for (page_id, disk_offset) in checkpoint.leaves {
+            mapping_table.insert(page_id as usize, None, disk_offset)?;
+        }
+        let page_id_allocator = PageIdAllocator::new(checkpoint.next_page_id as usize);
+        for page_id in checkpoint.free_page_ids {
+            page_id_allocator.free(page_id as usize);
+        }
+        let tree = Self::from_parts(storage, wal, options, inner, buffer_pool, mapping_table, page_id_allocator);
+
+        // Replayed records are already in the log
+        for record in wal_records.into_iter().filter(|r| r.lsn > checkpoint.wal_lsn) {
+            tree.check_record_size(&record.key, &record.value)?;
+            tree.buffer_record(&record.key, &record.value, record.record_type, false, true)?;
+        }
+        Ok(tree)
+    }
+
+    /// Assembles a tree from its parts; the mapping table must be over the
+    /// buffer pool's memory, and caches no mini-page yet.
+    pub(crate) fn from_parts(
+        storage: PageFile,
+        wal: Wal,
+        options: BfTreeOptions,
+        inner: InnerTree,
+        buffer_pool: BufferPool,
+        mapping_table: MappingTable,
+        page_id_allocator: PageIdAllocator,
+    ) -> Self {
+        Self {
+            mapping_table,
+            inner,
+            storage,
+            buffer_pool: Mutex::new(buffer_pool),
+            page_id_allocator,
+            wal,
+            options,
+            structure_version: AtomicU64::new(0),
+            quiesce: RwLock::new(()),
+            checkpointing: AtomicBool::new(false),
+            merge_signal: Mutex::new(None),
+        }
+    }
+
+    /// The options the tree was created or opened with.
+    pub fn options(&self) -> &BfTreeOptions {
+        &self.options
+    }
+
+    /// Test hook: the page file holding the leaf pages.
+    #[cfg(feature = "test-hooks")]
+    #[doc(hidden)]
+    pub fn storage(&self) -> &PageFile {
+        &self.storage
+    }
+
+    /// Test hook: the mapping table of the leaf pages.
+    #[cfg(feature = "test-hooks")]
+    #[doc(hidden)]
+    pub fn mapping_table(&self) -> &MappingTable {
+        &self.mapping_table
+    }
+
+    /// Test hook: the inner nodes routing keys to leaf pages.
+    #[cfg(feature = "test-hooks")]
+    #[doc(hidden)]
+    pub fn inner(&self) -> &InnerTree {
+        &self.inner
+    }
+
+    /// Test hook: the memory budget of the mini-pages.
+    #[cfg(feature = "test-hooks")]
+    #[doc(hidden)]
+    pub fn buffer_pool(&self) -> &Mutex<BufferPool> {
+        &self.buffer_pool
+    }
+
+    /// Test hook: the allocator of logical page IDs.
+    #[cfg(feature = "test-hooks")]
+    #[doc(hidden)]
+    pub fn page_id_allocator(&self) -> &PageIdAllocator {
+        &self.page_id_allocator
+    }
+
+    /// Test hook: the write-ahead log.
+    #[cfg(feature = "test-hooks")]
+    #[doc(hidden)]
+    pub fn wal(&self) -> &Wal {
+        &self.wal
+    }
+
+    /// Checkpoints the tree and closes it.
+    pub fn close(self) -> Result<()> {
+        self.checkpoint()
+    }
+
+    /// Merges every dirty mini-page into its leaf page, syncs the page file,
+    /// writes a Checkpoint of the tree structure and empties the write-ahead log.
+    /// Pages freed since the last checkpoint, and those of the checkpoint that
+    /// drops out of the file header, become reusable.
+    /// Inserts and deletes wait until the checkpoint is written; lookups and
+    /// scans keep running. Clean mini-pages stay cached: lookups keep adding
+    /// them, so waiting for an empty pool would not finish while they run.
+    ///
+    /// Freed pages are only reused and the log only shrinks at a checkpoint.
+    /// Writers take one once the log and the pages freed since the last one
+    /// exceed options.checkpoint_threshold bytes; with a threshold of 0 the
+    /// caller must checkpoint periodically, or the files grow without bound.
+    pub fn checkpoint(&self) -> Result<()> {
+        let _quiesce = self.quiesce.write().unwrap();
+        // Lookups only add clean records, so one pass leaves no dirty mini-page
+        let slots = self.buffer_pool.lock().unwrap().live_slots();
+        for (addr, page_id, _) in slots {
+            if let Some(mut entry) = self.mapping_table.lock(page_id) {
+                self.merge_if_dirty(page_id, addr, &mut entry)?;
+            }
+        }
+        self.storage.sync()?;
+
+        // The checkpoint that drops out of the header is not needed once this one commits
+        let header = self.storage.header();
+        let retired_pages: Vec<u64> = header.replaced_checkpoint().pages(self.storage.page_size()).collect();
+        let mut free_pages = self.storage.free_pages_at_checkpoint();
+        free_pages.extend(&retired_pages);
+
+        let next_page_id = self.page_id_allocator.next_id() as u64;
+        let inner_nodes = self.inner.to_nodes();
+        let leaves: Vec<(u64, u64)> = self
+            .mapping_table
+            .entries()
+            .map(|(page_id, (_, disk_offset))| (page_id as u64, disk_offset))
+            .collect();
+        // IDs of leaves merged away are free
+        let used: HashSet<u64> = leaves.iter().map(|(page_id, _)| *page_id)
+            .chain(inner_nodes.iter().map(|(page_id, _)| *page_id))
+            .collect();
+        let checkpoint = Checkpoint {
+            sequence: header.next_checkpoint_sequence(),
+            wal_lsn: self.wal.next_lsn() - 1,
+            next_page_id,
+            free_page_ids: (0..next_page_id).filter(|page_id| !used.contains(page_id)).collect(),
+            inner_nodes,
+            leaves,
+            page_end: self.storage.allocated_end(),
+            free_pages,
+        };
+
+        // The checkpoint becomes current once the header points at it
+        let pointer = checkpoint.write(&self.storage)?;
+        self.storage.update_header(header.with_checkpoint(pointer))?;
+        // Pages freed before the checkpoint are no longer referenced by it
+        for offset in retired_pages {
+            self.storage.free_page(offset);
+        }
+        self.storage.release_freed_pages();
+        self.wal.checkpoint()
+    }
+
+    /// Forces every logged insert and delete to stable storage,
+    /// regardless of the WAL sync policy.
+    pub fn sync_wal(&self) -> Result<()> {
+        self.wal.sync()
+    }
+
     /// Get operation as per Bf-Tree design.
     /// Supports caching positive and negative lookups into mini-pages with small probability.
     /// - Searches mini-page first (if present).
     /// - Falls back to leaf page on disk.
-    /// - With 1% chance, caches result (as Cache or Phantom).
-    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
-        // Traverse the tree to get the mini-page (if cached), leaf disk offset, and page ID.
-        let (mini_page_rc_opt, leaf_disk_offset, page_id) = self.traverse(key);
-
-        // Step 1: Search mini-page (memory cache)
-        if let Some(ref mini_page_rc) = mini_page_rc_opt {
-            let mini_page = mini_page_rc.borrow();
-            if let Some(value) = mini_page.binary_search(key) {
-                // Found in mini-page → return immediately
-                return Some(value);
-            }
-        }
-
-        // Step 2: Search leaf page on disk
-        let leaf_page = LeafPage::load_from_disk(leaf_disk_offset);
-        if let Some(value) = leaf_page.binary_search(key) {
-            // Found in leaf page
-            // Step 3: With small probability, cache it in the mini-page
-            if rand::random::<f64>() < 0.01 {
-                if let Some(ref mini_page_rc) = mini_page_rc_opt {
-                    let mut mini_page = mini_page_rc.borrow_mut();
-                    if mini_page.insert(key, &value, RecordType::Cache) {
-                        // Successfully cached in mini-page
-                        return Some(value);
-                    }
+    /// - With options.cache_probability chance, caches result (as Cache or Phantom).
+    ///   Caching is opportunistic: it never evicts or merges to make room.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.with_leaf(key, |page_id, entry, fences| {
+            // Step 1: Search mini-page (memory cache); a hit sets the record's reference bit
+            if let Some((_, mini_page)) = entry.mini_page.as_mut() {
+                match mini_page.reference(key) {
+                    // Tombstone or cached negative lookup → key is definitely absent
+                    Some((RecordType::Tombstone, _)) | Some((RecordType::Phantom, _)) => return Ok(None),
+                    // Found in mini-page → return immediately
+                    Some((_, value)) => return Ok(Some(value)),
+                    None => {}
+                }
+            }
+
+            // Step 2: Search leaf page on disk
+            let leaf_page = LeafPage::load_from_disk(&self.storage, entry.disk_offset)?;
+            if !leaf_page.page.covers(key) {
+                return Err(Error::Corruption(format!(
+                    "leaf page at offset {} does not cover the key it was routed",
+                    entry.disk_offset
+                )));
+            }
+            let value = leaf_page.binary_search(key);
+
+            // Step 3: With small probability, cache the result in the mini-page:
+            // a hit as a Cache record, a negative search as a Phantom record
+            if rand::random::<f64>() < self.options.cache_probability {
+                match &value {
+                    Some(value) => self.cache_record(page_id, entry, fences, key, value, RecordType::Cache),
+                    None => self.cache_record(page_id, entry, fences, key, &[], RecordType::Phantom),
+                }
+            }
+            Ok(value)
+        })
+    }
+
+    /// Insert operation as per Bf-Tree design.
+    /// Buffers inserts into mini-pages before flushing to the leaf page.
+    /// If no mini-page exists or current one is full, handles growth, merge, and replacement.
+    /// Fails with Error::KeyTooLarge if the record cannot fit a mini-page of the maximum size.
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.log_and_buffer(key, value, RecordType::Insert)
+    }
+
+    /// Delete operation as per Bf-Tree design.
+    /// Buffers a Tombstone record in the mini-page; the key is removed
+    /// from the leaf page when the mini-page is merged.
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.log_and_buffer(key, &[], RecordType::Tombstone)
+    }
+
+    /// Buffers a dirty record (Insert or Tombstone) and appends it to the WAL.
+    /// Oversized records are refused before they reach the log.
+    fn log_and_buffer(&self, key: &[u8], value: &[u8], record_type: RecordType) -> Result<()> {
+        self.check_record_size(key, value)?;
+        let mut merge_inline = false;
+        loop {
+            let full_page = {
+                let _quiesce = self.quiesce.read().unwrap();
+                self.buffer_record(key, value, record_type, true, merge_inline)?
+            };
+            let page_id = match full_page {
+                Some(page_id) => page_id,
+                None => return self.checkpoint_if_due(),
+            };
+            // Wait outside the quiesce lock, so checkpoints and the worker's pass can run
+            let signal = self.merge_signal.lock().unwrap().clone();
+            merge_inline = !signal.is_some_and(|signal| signal.wait_for_merge(page_id));
+        }
+    }
 
-                    // If mini-page is full and cannot insert, try resizing
-                    let new_size = mini_page.next_size();
-                    if new_size == 0 {
-                        // Mini-page cannot grow further → merge and reset
-                        mini_page.merge();
-                        self.mapping_table.clear_mini_page(page_id);
-
-                        let mut new_mini = MiniPage::new(leaf_disk_offset);
-                        if new_mini.insert(key, &value, RecordType::Cache) {
-                            self.mapping_table.update_mini_page(
-                                page_id,
-                                Rc::new(RefCell::new(new_mini)),
-                            );
-                        }
-                    } else {
-                        // Resize and reattempt insert
-                        mini_page.resize(new_size as usize);
-                        mini_page.insert(key, &value, RecordType::Cache);
+    /// Takes a checkpoint once the log and the pages freed since the last one
+    /// exceed options.checkpoint_threshold bytes. Only one writer takes it;
+    /// writers that find it running carry on.
+    fn checkpoint_if_due(&self) -> Result<()> {
+        let threshold = self.options.checkpoint_threshold as u64;
+        if threshold == 0 {
+            return Ok(());
+        }
+        let freed_bytes = (self.storage.freed_page_count() * self.storage.page_size()) as u64;
+        if self.wal.len() + freed_bytes <= threshold || self.checkpointing.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let result = self.checkpoint();
+        self.checkpointing.store(false, Ordering::Release);
+        result
+    }
+
+    /// Fails with Error::KeyTooLarge if the record cannot fit a mini-page of the maximum size.
+    fn check_record_size(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let max_size = self.options.mini_page_max_size;
+        if !MiniPage::fits_record(key, value, max_size) {
+            return Err(Error::KeyTooLarge { record_size: key.len() + value.len(), max_size });
+        }
+        Ok(())
+    }
+
+    /// Buffers a dirty record (Insert or Tombstone) into the key's mini-page.
+    /// With log set, the record is appended to the WAL under the page latch,
+    /// so the log holds the records of a key in the order they were applied,
+    /// and committed once the latch is released.
+    /// Unless merge_inline is set, a mini-page that cannot grow is left to a
+    /// running BackgroundMerger: returns its page ID without buffering the record.
+    fn buffer_record(&self, key: &[u8], value: &[u8], record_type: RecordType, log: bool, merge_inline: bool) -> Result<Option<usize>> {
+        loop {
+            let buffered = self.with_leaf(key, |page_id, entry, fences| {
+                self.buffer_into_mini_page(page_id, entry, fences, key, value, record_type, log, merge_inline)
+            })?;
+            match buffered {
+                Buffered::Done(lsn) => {
+                    if let Some(lsn) = lsn {
+                        self.wal.commit(lsn)?;
                     }
-                } else {
-                    // No existing mini-page → create one and insert
-                    let mut new_mini = MiniPage::new(leaf_disk_offset);
-                    if new_mini.insert(key, &value, RecordType::Cache) {
-                        self.mapping_table.update_mini_page(
-                            page_id,
-                            Rc::new(RefCell::new(new_mini)),
-                        );
+                    return Ok(None);
+                }
+                // Evict without holding the page latch, then try again
+                Buffered::NeedSpace(size) => self.make_room(size)?,
+                Buffered::Reroute => {}
+                Buffered::Full(page_id) => return Ok(Some(page_id)),
+            }
+        }
+    }
+
+    /// Inserts a record into the page's mini-page, creating, growing or merging it as needed.
+    /// A new mini-page gets the leaf's fences. Called with the page latch held.
+    #[allow(clippy::too_many_arguments)]
+    fn buffer_into_mini_page(
+        &self,
+        page_id: usize,
+        entry: &mut PageEntry,
+        fences: &Fences,
+        key: &[u8],
+        value: &[u8],
+        record_type: RecordType,
+        log: bool,
+        merge_inline: bool,
+    ) -> Result<Buffered> {
+        // Step 1: If a mini-page is already cached
+        if let Some((addr, mini_page)) = entry.mini_page.as_mut() {
+            // Insert into the existing mini-page if the record fits
+            if mini_page.can_insert(key, value, record_type) {
+                let lsn = self.log_record(log, record_type, key, value)?;
+                mini_page.insert(key, value, record_type);
+                return Ok(Buffered::Done(lsn));
+            }
+
+            // Step 2: If mini-page is full, try to grow its size
+            let new_size = mini_page.next_size(self.options.mini_page_max_size) as usize;
+            if new_size > 0 {
+                // Move the mini-page to a bigger slot and retry
+                let mut buffer_pool = self.buffer_pool.lock().unwrap();
+                let new_addr = match buffer_pool.allocate(page_id, new_size) {
+                    Some(new_addr) => new_addr,
+                    None => return Ok(Buffered::NeedSpace(new_size)),
+                };
+                buffer_pool.release(page_id, *addr);
+                drop(buffer_pool);
+                mini_page.resize(new_size);
+                *addr = new_addr;
+                return self.buffer_into_mini_page(page_id, entry, fences, key, value, record_type, log, merge_inline);
+            }
+
+            // Cannot grow further: the background worker merges it if one runs
+            if !merge_inline && self.merge_signal.lock().unwrap().is_some() {
+                return Ok(Buffered::Full(page_id));
+            }
+
+            // Otherwise the writer must merge dirty records into the leaf page.
+            // Terminates: a merged mini-page only holds hot records with cleared
+            // reference bits, which the next merge drops.
+            let (addr, mini_page) = entry.mini_page.take().unwrap();
+            self.buffer_pool.lock().unwrap().release(page_id, addr);
+            self.merge_mini_page(page_id, entry, mini_page)?;
+            return Ok(Buffered::Reroute);
+        }
+
+        // Step 3: No mini-page exists → create one sized for the record
+        let max_size = self.options.mini_page_max_size;
+        let mini_page = MiniPage::with_record(entry.disk_offset, fences, key, value, record_type, &self.options)
+            .ok_or(Error::KeyTooLarge { record_size: key.len() + value.len(), max_size })?;
+        let size = mini_page.page.node_meta.node_size as usize;
+        let addr = match self.buffer_pool.lock().unwrap().allocate(page_id, size) {
+            Some(addr) => addr,
+            None => return Ok(Buffered::NeedSpace(size)),
+        };
+        let lsn = match self.log_record(log, record_type, key, value) {
+            Ok(lsn) => lsn,
+            Err(e) => {
+                self.buffer_pool.lock().unwrap().release(page_id, addr);
+                return Err(e);
+            }
+        };
+        entry.mini_page = Some((addr, mini_page));
+        Ok(Buffered::Done(lsn))
+    }
+
+    /// Appends the record to the WAL if log is set, returning its LSN.
+    fn log_record(&self, log: bool, record_type: RecordType, key: &[u8], value: &[u8]) -> Result<Option<u64>> {
+        if !log {
+            return Ok(None);
+        }
+        self.wal.append(record_type, key, value).map(Some)
+    }
+
+    /// Caches a clean record (Cache or Phantom) in the page's mini-page when
+    /// it fits without growing, or in a new mini-page when the pool has room.
+    fn cache_record(
+        &self,
+        page_id: usize,
+        entry: &mut PageEntry,
+        fences: &Fences,
+        key: &[u8],
+        value: &[u8],
+        record_type: RecordType,
+    ) {
+        match entry.mini_page.as_mut() {
+            Some((_, mini_page)) => {
+                if mini_page.can_insert(key, value, record_type) {
+                    mini_page.insert(key, value, record_type);
+                }
+            }
+            None => {
+                if let Some(mini_page) = MiniPage::with_record(entry.disk_offset, fences, key, value, record_type, &self.options) {
+                    let size = mini_page.page.node_meta.node_size as usize;
+                    if let Some(addr) = self.buffer_pool.lock().unwrap().allocate(page_id, size) {
+                        entry.mini_page = Some((addr, mini_page));
                     }
                 }
             }
+        }
+    }
+
+    /// Evicts from the buffer pool tail until size bytes can be allocated.
+    /// Must be called without holding a page latch.
+    ///
+    /// Victims only get a second chance during the first pass over the pool:
+    /// readers that keep referencing records would otherwise refill it forever.
+    ///
+    /// Slots other threads are evicting are reclaimed once their merges
+    /// finish, so those are waited for before giving up.
+    fn make_room(&self, size: usize) -> Result<()> {
+        let mut second_chances = self.buffer_pool.lock().unwrap().len();
+        while !self.buffer_pool.lock().unwrap().can_allocate(size) {
+            let second_chance = second_chances > 0;
+            second_chances = second_chances.saturating_sub(1);
+            if !self.evict_one(second_chance)? {
+                if self.buffer_pool.lock().unwrap().is_empty() {
+                    return Err(Error::InvalidState(format!("buffer pool cannot fit a {} byte mini-page", size)));
+                }
+                std::thread::yield_now();
+            }
+        }
+        Ok(())
+    }
 
-            // Return the value retrieved from leaf
-            return Some(value);
+    /// On-demand eviction pass: evicts from the buffer pool tail until at most
+    /// target_used_bytes are in use. Referenced records get a second chance.
+    /// Runs alongside inserts and deletes, and waits for checkpoints, which
+    /// must not see leaves move or pages freed while they are written.
+    pub fn run_eviction(&self, target_used_bytes: usize) -> Result<()> {
+        let _quiesce = self.quiesce.read().unwrap();
+        while self.buffer_pool.lock().unwrap().used_bytes() > target_used_bytes {
+            if !self.evict_one(true)? {
+                break;
+            }
         }
+        Ok(())
+    }
 
-        // Step 4: Not found in mini or leaf → it's a negative search
-        // With small probability, cache the negative result as a Phantom record
-        if rand::random::<f64>() < 0.01 {
-            if let Some(ref mini_page_rc) = mini_page_rc_opt {
-                let mut mini_page = mini_page_rc.borrow_mut();
-                mini_page.insert(key, &[], RecordType::Phantom);
-            } else {
-                let mut new_mini = MiniPage::new(leaf_disk_offset);
-                if new_mini.insert(key, &[], RecordType::Phantom) {
-                    self.mapping_table.update_mini_page(
-                        page_id,
-                        Rc::new(RefCell::new(new_mini)),
-                    );
+    /// One pass of the background merge worker (see BackgroundMerger), within
+    /// io_budget bytes, each merge counting as one leaf page read and written:
+    /// - dirty mini-pages that grew to options.mini_page_watermark are merged
+    ///   into their leaves and stay cached with their hot records, so writers
+    ///   rarely find a mini-page that can neither grow nor take their record
+    /// - while the buffer pool is fuller than options.pool_watermark, mini-pages
+    ///   are evicted from the tail, so writers rarely wait for room
+    ///
+    /// Mini-pages that writers wait on because they cannot grow are merged
+    /// first, even past the budget.
+    ///
+    /// Runs alongside inserts and deletes, and waits for checkpoints. Latches
+    /// of dirty mini-pages are only tried, so the pass skips pages a writer holds.
+    /// Returns the bytes of the budget used.
+    pub fn background_merge(&self, options: &BackgroundMergeOptions, io_budget: usize) -> Result<usize> {
+        let _quiesce = self.quiesce.read().unwrap();
+        let merge_cost = 2 * self.storage.page_size();
+        let mut used = 0;
+
+        let signal = self.merge_signal.lock().unwrap().clone();
+        if let Some(signal) = signal {
+            let full_pages = signal.full_pages();
+            for &page_id in &full_pages {
+                if let Some(mut entry) = self.mapping_table.lock(page_id) {
+                    if self.merge_if_full(page_id, &mut entry)? {
+                        used += merge_cost;
+                    }
                 }
             }
+            signal.merged(&full_pages);
         }
 
-        // Final result: not found
-        None
+        let watermark_size = (self.options.mini_page_max_size as f64 * options.mini_page_watermark) as usize;
+        let slots = self.buffer_pool.lock().unwrap().live_slots();
+        for (addr, page_id, _) in slots.into_iter().filter(|&(_, _, size)| size >= watermark_size) {
+            if used + merge_cost > io_budget {
+                return Ok(used);
+            }
+            let mut entry = match self.mapping_table.try_lock(page_id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if self.merge_if_dirty(page_id, addr, &mut entry)? {
+                used += merge_cost;
+            }
+        }
+
+        let target_used_bytes = (self.buffer_pool.lock().unwrap().capacity() as f64 * options.pool_watermark) as usize;
+        while used + merge_cost <= io_budget && self.buffer_pool.lock().unwrap().used_bytes() > target_used_bytes {
+            if !self.evict_one(true)? {
+                break;
+            }
+            used += merge_cost;
+        }
+        Ok(used)
     }
 
-    /// Insert operation as per Bf-Tree design.
-    /// Buffers inserts into mini-pages before flushing to the leaf page.
-    /// If no mini-page exists or current one is full, handles growth, merge, and replacement.
-    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
-        // Step 1: Traverse the tree to locate:
-        // - mini_page_rc_opt: in-memory cached mini-page (if any)
-        // - leaf_disk_offset: disk location of the associated leaf page
-        // - page_id: logical page ID (used for mapping table updates)
-        let (mini_page_rc_opt, leaf_disk_offset, page_id) = self.traverse(key);
-
-        // Step 2: If a mini-page is already cached
-        if let Some(ref mini_page_rc) = mini_page_rc_opt {
-            let mut mini_page = mini_page_rc.borrow_mut();
-
-            // Try to insert into the existing mini-page
-            if mini_page.insert(key, value, RecordType::Insert) {
-                // Insert succeeded — done
-                return;
-            }
-
-            // Step 3: If mini-page is full, try to grow its size
-            let new_size = mini_page.next_size();
-
-            if new_size == 0 {
-                // Cannot grow further — must merge dirty records into the leaf page
-                mini_page.merge();
-
-                // Clear the old mini-page from the mapping table
-                self.mapping_table.clear_mini_page(page_id);
-
-                // Create a new mini-page and insert into it
-                let mut new_mini = MiniPage::new(leaf_disk_offset);
-                if new_mini.insert(key, value, RecordType::Insert) {
-                    self.mapping_table.update_mini_page(
-                        page_id,
-                        Rc::new(RefCell::new(new_mini)),
-                    );
+    /// Merges the page's mini-page into its leaf if it cannot grow any more, as
+    /// a writer would that finds no room for its record. Called with the page
+    /// latch held. Returns false if the mini-page can still grow.
+    fn merge_if_full(&self, page_id: usize, entry: &mut PageEntry) -> Result<bool> {
+        match &entry.mini_page {
+            Some((_, mini_page)) if mini_page.next_size(self.options.mini_page_max_size) == 0 => {}
+            _ => return Ok(false),
+        }
+        let (addr, mini_page) = entry.mini_page.take().unwrap();
+        self.buffer_pool.lock().unwrap().release(page_id, addr);
+        self.merge_mini_page(page_id, entry, mini_page)?;
+        Ok(true)
+    }
+
+    /// Merges the page's mini-page into its leaf if it is dirty and still at addr.
+    /// Called with the page latch held. Returns false if there was nothing to merge:
+    /// the mini-page may have moved or been merged since addr was listed.
+    fn merge_if_dirty(&self, page_id: usize, addr: u64, entry: &mut PageEntry) -> Result<bool> {
+        match &entry.mini_page {
+            Some((current, mini_page)) if *current == addr && mini_page.is_dirty() => {}
+            _ => return Ok(false),
+        }
+        let (addr, mini_page) = entry.mini_page.take().unwrap();
+        self.buffer_pool.lock().unwrap().release(page_id, addr);
+        self.merge_mini_page(page_id, entry, mini_page)?;
+        Ok(true)
+    }
+
+    /// Evicts the mini-page at the buffer pool tail with second-chance semantics:
+    /// - dirty records are merged into the leaf page
+    /// - records referenced since the last pass stay cached, with the bit cleared,
+    ///   and the shrunken mini-page moves to the head of the pool
+    /// - everything else is dropped
+    ///
+    /// Without second_chance, referenced records are dropped too.
+    /// Returns false if every cached mini-page is already being evicted.
+    fn evict_one(&self, second_chance: bool) -> Result<bool> {
+        let (victim_addr, victim_page_id) = match self.buffer_pool.lock().unwrap().evict_tail() {
+            Some(slot) => slot,
+            None => return Ok(false),
+        };
+        let mut entry = match self.mapping_table.lock(victim_page_id) {
+            Some(entry) => entry,
+            None => return Ok(true),
+        };
+
+        // The mini-page may have moved to a bigger slot in the meantime, which
+        // released this one
+        let mut victim = match entry.mini_page.take() {
+            Some((addr, mini_page)) if addr == victim_addr => mini_page,
+            other => {
+                entry.mini_page = other;
+                return Ok(true);
+            }
+        };
+        self.buffer_pool.lock().unwrap().release(victim_page_id, victim_addr);
+
+        if !second_chance {
+            victim.clear_references();
+        }
+        // Terminates: the reference bits are now clear, so a reinstalled
+        // victim is dropped the next time it reaches the tail.
+        self.merge_mini_page(victim_page_id, &mut entry, victim)?;
+        Ok(true)
+    }
+
+    /// Merges a mini-page (already released from the buffer pool) into its leaf.
+    /// Called with the page latch held.
+    /// - A split leaf moves to a new disk offset, freeing the old page; new
+    ///   leaves from the split get page IDs and are linked into the parent InnerNode
+    /// - With options.copy_on_write, a leaf that does not split moves as well;
+    ///   the new offset is published when the latch is released
+    /// - A leaf that deletes left underfull is merged with or borrows from a
+    ///   neighbour (see rebalance_leaf)
+    /// - Hot records that survive the merge are reinstalled in a shrunken
+    ///   mini-page if the pool has room without evicting; they are clean, so
+    ///   otherwise they are dropped
+    ///
+    /// If the merge fails, the unchanged mini-page is put back when the pool has
+    /// room for it without evicting, so its dirty records are not dropped.
+    fn merge_mini_page(&self, page_id: usize, entry: &mut PageEntry, mut mini_page: MiniPage) -> Result<()> {
+        // Only deletes make a leaf shrink
+        let had_tombstones = mini_page.page.kv_metas.iter().any(|kv| kv.record_type() == RecordType::Tombstone);
+        let new_siblings = match mini_page.merge(&self.storage, self.options.copy_on_write) {
+            Ok(new_siblings) => new_siblings,
+            Err(e) => {
+                self.reinstall(page_id, entry, mini_page);
+                return Err(e);
+            }
+        };
+
+        // The old page is reused once a checkpoint without it commits
+        let old_offset = entry.disk_offset;
+        entry.disk_offset = mini_page.page.node_meta.leaf;
+        if entry.disk_offset != old_offset {
+            self.storage.free_page(old_offset);
+        }
+        if !new_siblings.is_empty() {
+            // Right to left: new leaves are not latched, so each one only becomes
+            // reachable once the separator above its high fence is in place
+            for (separator, disk_offset) in new_siblings.into_iter().rev() {
+                // The new leaf is mapped before any separator routes to it
+                let new_page_id = self.page_id_allocator.allocate();
+                self.mapping_table.insert(new_page_id, None, disk_offset)?;
+                self.inner.insert_separator(
+                    separator,
+                    new_page_id as u64,
+                    self.options.inner_node_size,
+                    &self.page_id_allocator,
+                )?;
+            }
+            self.structure_version.fetch_add(1, Ordering::SeqCst);
+        } else if had_tombstones {
+            while self.rebalance_leaf(page_id, entry, &mut mini_page)? {}
+        }
+
+        if !mini_page.page.record_range().is_empty() {
+            mini_page.shrink_to_fit(self.options.mini_page_min_size);
+            self.reinstall(page_id, entry, mini_page);
+        }
+        Ok(())
+    }
+
+    /// Merges an underfull leaf with a neighbour under the same parent, or
+    /// borrows records from it if the two do not fit one page. Called with the
+    /// leaf's latch held after its mini-page was merged; mini_page holds the
+    /// hot records about to be reinstalled and is moved to the leaf's new fences.
+    /// - Merge: the separator leaves the parent InnerNode, and the neighbour's
+    ///   page ID and page are freed
+    /// - Borrow: the records are split evenly between the two leaves and the
+    ///   separator in the parent is replaced
+    ///
+    /// The result is written to freshly allocated pages, and the old ones are
+    /// freed. Dirty records of the neighbour's mini-page are written along, and
+    /// the mini-page dropped. The neighbour's latch is only tried: if it is held,
+    /// or either leaf has no fences, or the parent changed, the leaf is left as it is.
+    ///
+    /// Returns true if the leaf merged, as it may still underflow.
+    fn rebalance_leaf(&self, page_id: usize, entry: &mut PageEntry, mini_page: &mut MiniPage) -> Result<bool> {
+        let leaf_page = LeafPage::load_from_disk(&self.storage, entry.disk_offset)?;
+        if !leaf_page.is_underfull() || leaf_page.page.fence_count() == 0 {
+            return Ok(false);
+        }
+        let fences = leaf_page.page.fences();
+        let (routed_id, left, right) = self.inner.leaf_neighbours(&fences.low)?;
+        let (neighbour_id, separator, neighbour_is_right) = match (left, right) {
+            _ if routed_id != page_id as u64 => return Ok(false),
+            (_, Some((neighbour_id, separator))) => (neighbour_id, separator, true),
+            (Some((neighbour_id, separator)), None) => (neighbour_id, separator, false),
+            (None, None) => return Ok(false),
+        };
+        let mut neighbour = match self.mapping_table.try_lock(neighbour_id as usize) {
+            Some(neighbour) => neighbour,
+            None => return Ok(false),
+        };
+        let neighbour_page = LeafPage::load_from_disk(&self.storage, neighbour.disk_offset)?;
+        let neighbour_fences = neighbour_page.page.fences();
+        let (left_fences, right_fences) = match neighbour_is_right {
+            true => (&fences, &neighbour_fences),
+            false => (&neighbour_fences, &fences),
+        };
+        if neighbour_page.page.fence_count() == 0
+            || left_fences.high.as_ref() != Some(&separator)
+            || right_fences.low != separator
+        {
+            return Ok(false);
+        }
+
+        // Key ranges of the leaves are disjoint; the neighbour's dirty records go on top
+        let mut records: BTreeMap<Vec<u8>, Vec<u8>> = leaf_page.records().into_iter().chain(neighbour_page.records()).collect();
+        if let Some((_, neighbour_mini_page)) = &neighbour.mini_page {
+            let page = &neighbour_mini_page.page;
+            for i in page.record_range() {
+                match page.kv_metas[i].record_type() {
+                    RecordType::Insert => {
+                        records.insert(page.key_at(i).to_vec(), page.value_at(i).to_vec());
+                    }
+                    RecordType::Tombstone => {
+                        records.remove(page.key_at(i));
+                    }
+                    RecordType::Cache | RecordType::Phantom => {}
                 }
-            } else {
-                // Resize the mini-page to a larger size and retry the insert
-                mini_page.resize(new_size as usize);
-                mini_page.insert(key, value, RecordType::Insert);
             }
+        }
+        let merged_fences = Fences { low: left_fences.low.clone(), high: right_fences.high.clone() };
+        let pages = LeafPage::split(records.into_iter().collect(), &merged_fences, self.storage.page_size());
+        if pages.len() > 2 {
+            return Ok(false);
+        }
 
-            return; // Done after handling existing mini-page
+        let mut offsets = Vec::with_capacity(pages.len());
+        for (_, page) in &pages {
+            let offset = self.storage.allocate_page();
+            page.flush_to_disk(&self.storage, offset)?;
+            offsets.push(offset);
+        }
+        let applied = match &pages[..] {
+            [_] => self.inner.remove_separator(&separator, neighbour_id),
+            [_, (new_separator, _)] => {
+                self.inner.replace_separator(&separator, new_separator.clone(), self.options.inner_node_size)
+            }
+            _ => unreachable!(),
+        };
+        if !applied {
+            for offset in offsets {
+                self.storage.free_page(offset);
+            }
+            return Ok(false);
         }
 
-        // Step 4: No mini-page exists → create one and insert into it
-        let mut new_mini = MiniPage::new(leaf_disk_offset);
-        if new_mini.insert(key, value, RecordType::Insert) {
-            self.mapping_table.update_mini_page(
-                page_id,
-                Rc::new(RefCell::new(new_mini)),
-            );
+        self.storage.free_page(entry.disk_offset);
+        self.storage.free_page(neighbour.disk_offset);
+        if let Some((addr, _)) = neighbour.mini_page.take() {
+            self.buffer_pool.lock().unwrap().release(neighbour_id as usize, addr);
         }
+        // pages[0] is the left leaf
+        let (own, other) = if neighbour_is_right || pages.len() == 1 { (0, 1) } else { (1, 0) };
+        entry.disk_offset = offsets[own];
+        mini_page.set_fences(&pages[own].1.page.fences());
+        if pages.len() == 2 {
+            neighbour.disk_offset = offsets[other];
+        }
+
+        // Readers that routed to the merged away page ID retry once it reads as unmapped
+        self.structure_version.fetch_add(1, Ordering::SeqCst);
+        if pages.len() == 1 {
+            neighbour.unmap();
+            self.page_id_allocator.free(neighbour_id as usize);
+        }
+        Ok(pages.len() == 1)
     }
-    
-    /// Traverses the tree to resolve to mini-page (if cached) and leaf page disk offset.
-    /// Returns (Option<Rc<RefCell<MiniPage>>>, u64 disk_offset, usize page_id)
-    pub fn traverse(&self, key: &[u8]) -> (Option<Rc<RefCell<MiniPage>>>, u64, usize) {
-    // pub fn traverse(&self, key: &[u8]) -> (Option<MiniPage>, u64, usize) {
-        let mut current_node = &self.root_inner_node;
 
+    /// Puts a mini-page back into the page's entry if the pool has room without evicting.
+    fn reinstall(&self, page_id: usize, entry: &mut PageEntry, mini_page: MiniPage) {
+        let size = mini_page.page.node_meta.node_size as usize;
+        if let Some(addr) = self.buffer_pool.lock().unwrap().allocate(page_id, size) {
+            entry.mini_page = Some((addr, mini_page));
+        }
+    }
+
+    /// Routes key to its leaf and runs f on the leaf's page ID, entry and fences
+    /// (see InnerTree::route) with the page latch held.
+    /// If a split moved key to another leaf before the latch was taken, the key
+    /// is routed again, so f always sees the leaf that covers key.
+    pub(crate) fn with_leaf<T>(
+        &self,
+        key: &[u8],
+        mut f: impl FnMut(usize, &mut PageEntry, &Fences) -> Result<T>,
+    ) -> Result<T> {
         loop {
-            let child_page_id_opt = current_node.find_child_page_id(key);
-
-            if let Some(child_page_id) = child_page_id_opt {
-                // Try resolving child_page_id as an inner node first
-                if let Some(inner_node) = self.get_inner_node(child_page_id) {
-                    // Descend further in the tree
-                    current_node = inner_node;
-                } else {
-                    // Reached last-level inner node ➔ child_page_id references a mini/leaf page
-                    // Use mapping table to resolve to (mini-page pointer, disk offset)
-                    let page_id = child_page_id as usize;
-                    let mapping_entry = self.mapping_table.get(page_id);
-                    if let Some((mini_page_rc_opt, disk_offset)) = mapping_entry {
-                        // Return (mini-page pointer if cached, leaf page disk offset)
-                        return (mini_page_rc_opt.map(Rc::clone), disk_offset, page_id);
-                    } else {
-                        panic!("Page ID {} not found in mapping table", child_page_id);
-                    }
+            // Separators of a leaf split are inserted right to left before the version
+            // is bumped, so an unlatched new sibling is never routed with stale fences
+            let version = self.structure_version.load(Ordering::SeqCst);
+            let (page_id, fences) = self.inner.route(key)?;
+            let page_id = page_id as usize;
+            let mut entry = match self.mapping_table.lock(page_id) {
+                Some(entry) => entry,
+                // A leaf merge since routing may have unmapped the page ID
+                None if self.structure_version.load(Ordering::SeqCst) != version => continue,
+                None => return Err(Error::InvalidState(format!("page ID {} not found in mapping table", page_id))),
+            };
+
+            // Splits of this leaf happen under its latch, so a split that
+            // finished since routing has already bumped the version
+            if self.structure_version.load(Ordering::SeqCst) != version {
+                continue;
+            }
+            if let Some((_, mini_page)) = &entry.mini_page {
+                if !mini_page.page.covers(key) {
+                    return Err(Error::InvalidState(format!("mini-page of page ID {} does not cover the key it was routed", page_id)));
                 }
-            } else {
-                panic!("Invalid tree state: no child page ID found for key {:?}", key);
             }
+            return f(page_id, &mut entry, &fences);
         }
     }
 
-    /// Helper to get inner node by page ID.
-    ///
-    /// In Bf-Tree, inner nodes are pinned in memory and referenced directly by page_id.
-    /// Returns Some(&InnerNode) if page_id exists in pinned nodes, else None.
-    pub fn get_inner_node(&self, page_id: u64) -> Option<&InnerNode> {
-        // Check if page_id is the root node
-        if page_id == 0 {
-            Some(&self.root_inner_node)
-        } else {
-            // Lookup in the pinned inner_nodes HashMap
-            self.inner_nodes.get(&page_id)
+    /// Ordered scan over all live keys in range, e.g. `tree.range(&b"a"[..]..&b"m"[..])`.
+    /// Mini-page records take precedence over the leaf page contents.
+    /// Items are Results: a leaf that cannot be read ends the scan with an error.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> RangeIter<'_> {
+        RangeIter::new(self, range)
+    }
+
+    /// Ordered scan over every live key in the tree.
+    pub fn iter(&self) -> RangeIter<'_> {
+        self.range::<&[u8], _>(..)
+    }
+
+    /// Traverses the tree to resolve to mini-page (if cached) and leaf page disk offset.
+    /// Returns (Option<u64> mini-page address, u64 disk_offset, usize page_id),
+    /// or Error::InvalidState if the key routes to a missing child or page ID.
+    /// Takes no latches: the entry may be read while a writer holds the page.
+    pub fn traverse(&self, key: &[u8]) -> Result<(Option<u64>, u64, usize)> {
+        loop {
+            let version = self.structure_version.load(Ordering::SeqCst);
+            let (page_id, _) = self.inner.route(key)?;
+            let page_id = page_id as usize;
+            let mapping = self.mapping_table.get(page_id);
+            // A leaf split or merge since routing may have moved key to another leaf
+            if self.structure_version.load(Ordering::SeqCst) != version {
+                continue;
+            }
+            let (mini_page_addr_opt, disk_offset) = mapping.ok_or_else(|| {
+                Error::InvalidState(format!("page ID {} not found in mapping table", page_id))
+            })?;
+            return Ok((mini_page_addr_opt, disk_offset, page_id));
         }
     }
 
-}
+    /// A copy of the inner node with the given page ID; page ID 0 is the root.
+    pub fn get_inner_node(&self, page_id: u64) -> Option<InnerNode> {
+        self.inner.get(page_id)
+    }
 
+}
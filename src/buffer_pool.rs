@@ -0,0 +1,230 @@
+// src/buffer_pool.rs
+
+use std::collections::{HashMap, VecDeque};
+use std::ptr;
+use std::sync::Arc;
+
+/// One allocation in the circular buffer.
+struct Slot {
+    addr: u64,       // physical offset within the buffer
+    size: usize,     // bytes reserved (mini-page node_size, or wrap-around padding)
+    page_id: usize,  // owner of the mini-page
+    live: bool,      // false once released (or for padding)
+    evicting: bool,  // handed out by evict_tail, not released yet
+}
+
+/// The contiguous region of capacity bytes the mini-pages are stored in.
+/// The BufferPool hands out its slots; the MappingTable reads a page's
+/// mini-page out of its slot when the page is latched and writes it back
+/// when the latch is released. Nothing else touches the bytes, so a slot
+/// needs no lock beyond its page's latch.
+pub struct PoolMemory {
+    bytes: *mut u8,
+    len: usize,
+}
+
+// SAFETY: a slot is only read or written by the holder of its page's latch
+unsafe impl Send for PoolMemory {}
+unsafe impl Sync for PoolMemory {}
+
+impl PoolMemory {
+    fn new(len: usize) -> Self {
+        let bytes = Box::into_raw(vec![0u8; len].into_boxed_slice()) as *mut u8;
+        Self { bytes, len }
+    }
+
+    /// The size bytes at addr.
+    /// Panics if they run past the end of the region.
+    ///
+    /// # Safety
+    /// The caller must own the slot at addr: hold the latch of the page it was
+    /// allocated for, before the slot is released.
+    pub unsafe fn read(&self, addr: u64, size: usize) -> &[u8] {
+        assert!(addr as usize + size <= self.len, "{} bytes at {} run past the buffer", size, addr);
+        std::slice::from_raw_parts(self.bytes.add(addr as usize), size)
+    }
+
+    /// Copies bytes to addr.
+    /// Panics if they run past the end of the region.
+    ///
+    /// # Safety
+    /// As for read; bytes must also fit the slot.
+    pub unsafe fn write(&self, addr: u64, bytes: &[u8]) {
+        assert!(addr as usize + bytes.len() <= self.len, "{} bytes at {} run past the buffer", bytes.len(), addr);
+        ptr::copy_nonoverlapping(bytes.as_ptr(), self.bytes.add(addr as usize), bytes.len());
+    }
+}
+
+impl Drop for PoolMemory {
+    fn drop(&mut self) {
+        // SAFETY: allocated in new as a boxed slice of len bytes
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(self.bytes, self.len)) });
+    }
+}
+
+/// BufferPool allocates the mini-pages from one contiguous region of
+/// capacity bytes (see PoolMemory), managed as a variable-length circular buffer.
+/// - Each mini-page takes a slot of its node_size bytes allocated at the
+///   head; the oldest allocations sit at the tail. A mini-page that grows
+///   moves to a new slot, and its old one is released.
+/// - The MappingTable points into the region: a cached mini-page is
+///   referenced by its slot address.
+/// - When the budget is exhausted, the tail slot is handed to the caller,
+///   which merges that page's mini-page into its leaf and releases the slot.
+///   Until then its bytes stay reserved.
+///
+/// Live slots are indexed by address. The bookkeeping is shared behind a
+/// Mutex; the region itself is not (see PoolMemory).
+pub struct BufferPool {
+    capacity: usize,
+    memory: Arc<PoolMemory>,
+    head: u64,                // logical position of the next allocation
+    tail: u64,                // logical position of the oldest allocation
+    slots: VecDeque<Slot>,    // allocations in ring order (tail at front)
+    first_slot: u64,          // sequence number of the slot at the front
+    index: HashMap<u64, u64>, // address → sequence number of every live slot
+    evicting: usize,          // live slots handed out by evict_tail
+}
+
+impl BufferPool {
+    /// Creates a buffer pool over a region of capacity bytes.
+    /// The budget must hold at least one maximum-size mini-page.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            memory: Arc::new(PoolMemory::new(capacity)),
+            head: 0,
+            tail: 0,
+            slots: VecDeque::new(),
+            first_slot: 0,
+            index: HashMap::new(),
+            evicting: 0,
+        }
+    }
+
+    /// Byte budget of the buffer.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The region the slots are allocated from, for the MappingTable.
+    pub fn memory(&self) -> &Arc<PoolMemory> {
+        &self.memory
+    }
+
+    /// Bytes between tail and head, including released slots not yet reclaimed.
+    pub fn used_bytes(&self) -> usize {
+        (self.head - self.tail) as usize
+    }
+
+    /// Number of mini-pages currently cached.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Number of slots handed out by evict_tail that are not released yet.
+    pub fn evicting(&self) -> usize {
+        self.evicting
+    }
+
+    /// Bytes of padding needed before an allocation of size at the head.
+    /// Allocations never wrap: the remainder of the buffer is skipped if needed.
+    fn padding_for(&self, size: usize) -> u64 {
+        let capacity = self.capacity as u64;
+        let offset = self.head % capacity;
+        if offset + size as u64 > capacity { capacity - offset } else { 0 }
+    }
+
+    /// True if a mini-page of size bytes can be allocated without evicting.
+    pub fn can_allocate(&self, size: usize) -> bool {
+        self.used_bytes() + self.padding_for(size) as usize + size <= self.capacity
+    }
+
+    /// Reserves size bytes at the head of the buffer for page_id's mini-page
+    /// and returns the address. Returns None if there is not enough room;
+    /// the caller should evict from the tail and retry.
+    pub fn allocate(&mut self, page_id: usize, size: usize) -> Option<u64> {
+        if !self.can_allocate(size) {
+            return None;
+        }
+        let capacity = self.capacity as u64;
+        let padding = self.padding_for(size);
+
+        if padding > 0 {
+            self.slots.push_back(Slot { addr: self.head % capacity, size: padding as usize, page_id, live: false, evicting: false });
+            self.head += padding;
+        }
+
+        let addr = self.head % capacity;
+        self.slots.push_back(Slot { addr, size, page_id, live: true, evicting: false });
+        self.index.insert(addr, self.first_slot + self.slots.len() as u64 - 1);
+        self.head += size as u64;
+        Some(addr)
+    }
+
+    /// Releases page_id's slot at addr. Returns false if the slot was already
+    /// released or evicted; the address may since belong to another page,
+    /// which is why the owner must match.
+    pub fn release(&mut self, page_id: usize, addr: u64) -> bool {
+        let slot = match self.index.get(&addr) {
+            Some(&seq) => &mut self.slots[(seq - self.first_slot) as usize],
+            None => return false,
+        };
+        if slot.page_id != page_id {
+            return false;
+        }
+        slot.live = false;
+        if slot.evicting {
+            self.evicting -= 1;
+        }
+        self.index.remove(&addr);
+        self.reclaim();
+        true
+    }
+
+    /// Hands out the oldest live slot not handed out yet, so its mini-page can
+    /// be merged. Its bytes are reclaimed once the slot is released.
+    /// Returns (address, page_id), or None if no such slot is left.
+    pub fn evict_tail(&mut self) -> Option<(u64, usize)> {
+        let slot = self.slots.iter_mut().find(|s| s.live && !s.evicting)?;
+        slot.evicting = true;
+        self.evicting += 1;
+        Some((slot.addr, slot.page_id))
+    }
+
+    /// (address, page_id, size) of every live slot, oldest first.
+    pub fn live_slots(&self) -> Vec<(u64, usize, usize)> {
+        self.slots
+            .iter()
+            .filter(|s| s.live)
+            .map(|s| (s.addr, s.page_id, s.size))
+            .collect()
+    }
+
+    /// Returns (page_id, size) of the live slot at addr.
+    pub fn get(&self, addr: u64) -> Option<(usize, usize)> {
+        let slot = &self.slots[(*self.index.get(&addr)? - self.first_slot) as usize];
+        Some((slot.page_id, slot.size))
+    }
+
+    /// Advances the tail past released slots.
+    fn reclaim(&mut self) {
+        while let Some(slot) = self.slots.front() {
+            if slot.live {
+                break;
+            }
+            self.tail += slot.size as u64;
+            self.slots.pop_front();
+            self.first_slot += 1;
+        }
+        if self.slots.is_empty() {
+            // An empty ring restarts at offset 0, so a full-capacity allocation fits
+            self.head = self.head.next_multiple_of(self.capacity as u64);
+            self.tail = self.head;
+        }
+    }
+}
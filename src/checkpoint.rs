@@ -0,0 +1,283 @@
+// src/checkpoint.rs
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Write};
+
+use crate::crc32c::crc32c;
+use crate::error::{Error, Result};
+use crate::inner_node::InnerNode;
+use crate::storage::PageFile;
+
+pub const CHECKPOINT_MAGIC: [u8; 8] = *b"BFTCKPT\0";
+pub const CHECKPOINT_POINTER_SIZE: usize = 24;
+
+/// Where a checkpoint lives in the page file; kept in the FileHeader.
+/// A zero len means the slot holds no checkpoint.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckpointPointer {
+    pub sequence: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl CheckpointPointer {
+    pub fn serialize_into<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_u64::<LittleEndian>(self.sequence)?;
+        writer.write_u64::<LittleEndian>(self.offset)?;
+        writer.write_u64::<LittleEndian>(self.len)?;
+        Ok(())
+    }
+
+    pub fn deserialize(buf: &[u8; CHECKPOINT_POINTER_SIZE]) -> Result<Self> {
+        let mut cursor = Cursor::new(&buf[..]);
+        Ok(Self {
+            sequence: cursor.read_u64::<LittleEndian>()?,
+            offset: cursor.read_u64::<LittleEndian>()?,
+            len: cursor.read_u64::<LittleEndian>()?,
+        })
+    }
+
+    /// Offsets of the pages the checkpoint occupies.
+    pub fn pages(&self, page_size: usize) -> impl Iterator<Item = u64> {
+        (self.offset..self.offset + self.len).step_by(page_size)
+    }
+}
+
+/// Checkpoint is a snapshot of the in-memory tree structure:
+/// - every InnerNode by page ID (the root is page ID 0)
+/// - the leaf page ID → disk offset half of the mapping table
+/// - the next page ID the PageIdAllocator hands out, and the free IDs below it
+/// - the WAL LSN up to which records are contained in the leaf pages
+/// - the free pages of the page file, and the end of its allocated pages
+///
+/// It is written to freshly allocated pages and becomes current only once the
+/// FileHeader points at it, so a crash mid-write leaves the previous one intact.
+///
+/// Layout: magic, sequence, wal_lsn, next_page_id, inner node count u32,
+/// leaf count u32, (page ID, InnerNode) pairs, (page ID, disk offset) pairs,
+/// page_end, free page count u32, free page offsets, free page ID count u32,
+/// free page IDs, the sequence again, then a CRC-32C of everything before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub sequence: u64,
+    pub wal_lsn: u64,
+    pub next_page_id: u64,
+    pub inner_nodes: Vec<(u64, InnerNode)>,
+    pub leaves: Vec<(u64, u64)>,
+    pub page_end: u64,        // first page offset not allocated when the checkpoint was taken
+    pub free_pages: Vec<u64>, // pages free once the checkpoint is current, bar those it is written to
+    pub free_page_ids: Vec<u64>, // IDs below next_page_id of neither an inner node nor a leaf
+}
+
+impl Checkpoint {
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        buf.write_all(&CHECKPOINT_MAGIC)?;
+        buf.write_u64::<LittleEndian>(self.sequence)?;
+        buf.write_u64::<LittleEndian>(self.wal_lsn)?;
+        buf.write_u64::<LittleEndian>(self.next_page_id)?;
+        buf.write_u32::<LittleEndian>(self.inner_nodes.len() as u32)?;
+        buf.write_u32::<LittleEndian>(self.leaves.len() as u32)?;
+
+        for (page_id, inner_node) in &self.inner_nodes {
+            buf.write_u64::<LittleEndian>(*page_id)?;
+            inner_node.serialize_into(&mut buf)?;
+        }
+        for &(page_id, disk_offset) in &self.leaves {
+            buf.write_u64::<LittleEndian>(page_id)?;
+            buf.write_u64::<LittleEndian>(disk_offset)?;
+        }
+        buf.write_u64::<LittleEndian>(self.page_end)?;
+        buf.write_u32::<LittleEndian>(self.free_pages.len() as u32)?;
+        for &offset in &self.free_pages {
+            buf.write_u64::<LittleEndian>(offset)?;
+        }
+        buf.write_u32::<LittleEndian>(self.free_page_ids.len() as u32)?;
+        for &page_id in &self.free_page_ids {
+            buf.write_u64::<LittleEndian>(page_id)?;
+        }
+
+        buf.write_u64::<LittleEndian>(self.sequence)?;
+        let checksum = crc32c(&buf);
+        buf.write_u32::<LittleEndian>(checksum)?;
+        Ok(buf)
+    }
+
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        let truncated = |_| Error::Corruption("checkpoint is truncated".to_string());
+        if buf.len() < 12 || buf[..8] != CHECKPOINT_MAGIC {
+            return Err(Error::Corruption("checkpoint has a bad magic".to_string()));
+        }
+        let (body, checksum) = buf.split_at(buf.len() - 4);
+        if crc32c(body).to_le_bytes() != checksum {
+            return Err(Error::Corruption("checkpoint checksum mismatch".to_string()));
+        }
+        let mut cursor = Cursor::new(&body[8..]);
+
+        let sequence = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+        let wal_lsn = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+        let next_page_id = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+        let inner_node_count = cursor.read_u32::<LittleEndian>().map_err(truncated)?;
+        let leaf_count = cursor.read_u32::<LittleEndian>().map_err(truncated)?;
+
+        let mut inner_nodes = Vec::new();
+        for _ in 0..inner_node_count {
+            let page_id = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+            let start = cursor.position() as usize;
+            let (inner_node, len) = InnerNode::deserialize(&cursor.get_ref()[start..])?;
+            cursor.set_position((start + len) as u64);
+            inner_nodes.push((page_id, inner_node));
+        }
+
+        let mut leaves = Vec::new();
+        for _ in 0..leaf_count {
+            let page_id = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+            let disk_offset = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+            leaves.push((page_id, disk_offset));
+        }
+
+        let page_end = cursor.read_u64::<LittleEndian>().map_err(truncated)?;
+        let free_page_count = cursor.read_u32::<LittleEndian>().map_err(truncated)?;
+        let mut free_pages = Vec::new();
+        for _ in 0..free_page_count {
+            free_pages.push(cursor.read_u64::<LittleEndian>().map_err(truncated)?);
+        }
+        let free_page_id_count = cursor.read_u32::<LittleEndian>().map_err(truncated)?;
+        let mut free_page_ids = Vec::new();
+        for _ in 0..free_page_id_count {
+            free_page_ids.push(cursor.read_u64::<LittleEndian>().map_err(truncated)?);
+        }
+
+        if cursor.read_u64::<LittleEndian>().map_err(truncated)? != sequence {
+            return Err(Error::Corruption("checkpoint was torn while being written".to_string()));
+        }
+        if !inner_nodes.iter().any(|(page_id, _)| *page_id == 0) {
+            return Err(Error::Corruption("checkpoint has no root inner node".to_string()));
+        }
+
+        Ok(Self {
+            sequence,
+            wal_lsn,
+            next_page_id,
+            inner_nodes,
+            leaves,
+            page_end,
+            free_pages,
+            free_page_ids,
+        })
+    }
+
+    /// Checks that the checkpoint describes a tree that can be built without
+    /// trusting it: the page IDs below next_page_id are each used once, the
+    /// inner nodes form a tree below the root, and every page offset is an
+    /// allocated page of a file that ends at file_end.
+    pub fn validate(&self, page_size: usize, file_end: u64) -> Result<()> {
+        let corrupt = |message: String| Err(Error::Corruption(format!("checkpoint {}", message)));
+
+        let id_count = self.inner_nodes.len() + self.leaves.len() + self.free_page_ids.len();
+        if self.next_page_id != id_count as u64 {
+            return corrupt(format!("has next page ID {} but {} page IDs", self.next_page_id, id_count));
+        }
+        let mut seen = HashSet::new();
+        let page_ids = self.inner_nodes.iter().map(|(page_id, _)| *page_id)
+            .chain(self.leaves.iter().map(|(page_id, _)| *page_id))
+            .chain(self.free_page_ids.iter().copied());
+        for page_id in page_ids {
+            if page_id >= self.next_page_id || !seen.insert(page_id) {
+                return corrupt(format!("has page ID {} out of range or twice", page_id));
+            }
+        }
+
+        // Each inner node and leaf is the child of one inner node, and walking
+        // down from the root reaches every inner node
+        let inner_nodes: HashMap<u64, &InnerNode> = self.inner_nodes.iter().map(|(page_id, node)| (*page_id, node)).collect();
+        let leaves: HashSet<u64> = self.leaves.iter().map(|(page_id, _)| *page_id).collect();
+        let mut children = HashSet::new();
+        let mut stack = vec![0];
+        let mut reached = 0;
+        while let Some(page_id) = stack.pop() {
+            let node = match inner_nodes.get(&page_id) {
+                Some(node) => node,
+                None => return corrupt("has no root inner node".to_string()),
+            };
+            reached += 1;
+            for &child in &node.children {
+                if child == 0 || !children.insert(child) {
+                    return corrupt(format!("has page ID {} as a child twice", child));
+                }
+                if inner_nodes.contains_key(&child) {
+                    stack.push(child);
+                } else if !leaves.contains(&child) {
+                    return corrupt(format!("has child page ID {} that is neither an inner node nor a leaf", child));
+                }
+            }
+        }
+        if reached != inner_nodes.len() {
+            return corrupt("has inner nodes the root does not reach".to_string());
+        }
+
+        let page_size = page_size as u64;
+        if !self.page_end.is_multiple_of(page_size) || self.page_end > file_end {
+            return corrupt(format!("has page end {} past the file end {}", self.page_end, file_end));
+        }
+        let offsets = self.leaves.iter().map(|(_, offset)| *offset).chain(self.free_pages.iter().copied());
+        for offset in offsets {
+            if offset == 0 || !offset.is_multiple_of(page_size) || offset >= self.page_end {
+                return corrupt(format!("has page offset {} that is not an allocated page", offset));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the checkpoint to newly allocated, contiguous pages and syncs them.
+    /// Returns the pointer to store in the FileHeader.
+    pub fn write(&self, storage: &PageFile) -> Result<CheckpointPointer> {
+        let bytes = self.serialize()?;
+        let page_size = storage.page_size();
+        let page_count = bytes.len().div_ceil(page_size);
+        let offset = storage.allocate_pages(page_count);
+
+        for (i, chunk) in bytes.chunks(page_size).enumerate() {
+            let mut page = chunk.to_vec();
+            page.resize(page_size, 0);
+            storage.write_page(offset + (i * page_size) as u64, &page)?;
+        }
+        storage.sync()?;
+
+        Ok(CheckpointPointer {
+            sequence: self.sequence,
+            offset,
+            len: bytes.len() as u64,
+        })
+    }
+
+    /// Reads the checkpoint a FileHeader slot points at, and validates it
+    /// against the file.
+    pub fn read(storage: &PageFile, pointer: &CheckpointPointer) -> Result<Self> {
+        if pointer.offset.saturating_add(pointer.len) > storage.file_len()? {
+            return Err(Error::Corruption(format!(
+                "checkpoint at offset {} runs past the end of the file",
+                pointer.offset
+            )));
+        }
+        let page_size = storage.page_size();
+        let mut bytes = Vec::with_capacity(pointer.len as usize);
+        let mut offset = pointer.offset;
+        while (bytes.len() as u64) < pointer.len {
+            bytes.extend_from_slice(&storage.read_page(offset)?);
+            offset += page_size as u64;
+        }
+        bytes.truncate(pointer.len as usize);
+
+        let checkpoint = Self::deserialize(&bytes)?;
+        if checkpoint.sequence != pointer.sequence {
+            return Err(Error::Corruption(format!(
+                "checkpoint at offset {} has sequence {}, expected {}",
+                pointer.offset, checkpoint.sequence, pointer.sequence
+            )));
+        }
+        checkpoint.validate(page_size, storage.allocated_end())?;
+        Ok(checkpoint)
+    }
+}
@@ -1,6 +1,205 @@
 // src/config.rs
 
-pub const INNER_NODE_SIZE: usize = 4096; // size of inner nodes (fixed)
-pub const LEAF_PAGE_SIZE: usize = 4096; // size of leaf pages (fixed)
-pub const MINI_PAGE_MIN_SIZE: usize = 64; // minimum size of a mini-page
-pub const MINI_PAGE_MAX_SIZE: usize = 4096; // maximum size of a mini-page
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::inner_node::InnerNode;
+use crate::mini_page::MiniPage;
+use crate::wal::WalSyncPolicy;
+
+pub const INNER_NODE_SIZE: usize = 4096; // default size of inner nodes
+pub const LEAF_PAGE_SIZE: usize = 4096; // default size of leaf pages
+pub const MINI_PAGE_MIN_SIZE: usize = 64; // default minimum size of a mini-page
+pub const MINI_PAGE_MAX_SIZE: usize = 4096; // default maximum size of a mini-page
+pub const CACHE_PROBABILITY: f64 = 0.01; // default chance that get caches a leaf lookup
+pub const LEAF_UNDERFLOW_DIVISOR: usize = 4; // a leaf underflows when its live bytes fall under 1/4 of the page
+pub const CHECKPOINT_THRESHOLD: usize = 16 * 1024 * 1024; // default bytes of log and freed pages that trigger a checkpoint
+
+/// Runtime options for a BfTree.
+///
+/// Page and node sizes are fixed when a tree is created and persisted in the
+/// file header; `BfTree::open` uses the persisted sizes and takes only the
+/// runtime settings (buffer pool size, caching probability, WAL sync policy,
+/// copy-on-write flushes, checkpoint threshold) from the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BfTreeOptions {
+    pub leaf_page_size: usize,          // size of leaf pages on disk
+    pub inner_node_size: usize,         // size limit of inner nodes
+    pub mini_page_min_size: usize,      // size of a new mini-page
+    pub mini_page_max_size: usize,      // size at which a mini-page is merged
+    pub buffer_pool_size: usize,        // byte budget of the mini-page buffer pool
+    pub cache_probability: f64,         // chance that get caches a leaf lookup
+    pub wal_sync_policy: WalSyncPolicy, // when logged inserts and deletes are fsynced
+    pub copy_on_write: bool,            // merges move leaves to new pages instead of overwriting them
+    pub checkpoint_threshold: usize,    // bytes of log and freed pages after which a writer checkpoints; 0 never does
+}
+
+impl Default for BfTreeOptions {
+    fn default() -> Self {
+        Self {
+            leaf_page_size: LEAF_PAGE_SIZE,
+            inner_node_size: INNER_NODE_SIZE,
+            mini_page_min_size: MINI_PAGE_MIN_SIZE,
+            mini_page_max_size: MINI_PAGE_MAX_SIZE,
+            buffer_pool_size: 1024 * 1024,
+            cache_probability: CACHE_PROBABILITY,
+            wal_sync_policy: WalSyncPolicy::PerOp,
+            copy_on_write: false,
+            checkpoint_threshold: CHECKPOINT_THRESHOLD,
+        }
+    }
+}
+
+impl BfTreeOptions {
+    /// Starts a builder from the default options.
+    pub fn builder() -> BfTreeOptionsBuilder {
+        BfTreeOptionsBuilder { options: Self::default() }
+    }
+
+    /// Checks that the options describe a usable tree:
+    /// - page and node sizes are powers of two that fit the u16 NodeMeta node_size
+    /// - a full mini-page fits in a leaf page, and the buffer pool fits a full mini-page
+    /// - an inner node holds at least 3 separators of the longest key, so splits
+    ///   never leave a node without keys
+    /// - the caching probability is in [0, 1]
+    /// - a group commit syncs after at least one record
+    pub fn validate(&self) -> Result<()> {
+        check_size("leaf_page_size", self.leaf_page_size, 512)?;
+        check_size("inner_node_size", self.inner_node_size, 256)?;
+        check_size("mini_page_min_size", self.mini_page_min_size, 32)?;
+        check_size("mini_page_max_size", self.mini_page_max_size, 128)?; // room for fences
+
+        if self.mini_page_min_size > self.mini_page_max_size {
+            return Err(invalid("mini_page_min_size must not exceed mini_page_max_size".to_string()));
+        }
+        if self.mini_page_max_size > self.leaf_page_size {
+            return Err(invalid("mini_page_max_size must not exceed leaf_page_size".to_string()));
+        }
+        let max_key_size = MiniPage::max_key_size(self.mini_page_max_size);
+        let min_inner_node_size = InnerNode { keys: vec![vec![0; max_key_size]; 3], children: vec![0; 4] }.byte_size();
+        if self.inner_node_size < min_inner_node_size {
+            return Err(invalid(format!(
+                "inner_node_size {} cannot hold 3 keys of {} bytes, the longest key of mini_page_max_size {}; it needs {}",
+                self.inner_node_size, max_key_size, self.mini_page_max_size, min_inner_node_size
+            )));
+        }
+        if self.buffer_pool_size < self.mini_page_max_size {
+            return Err(invalid("buffer_pool_size must hold at least one mini_page_max_size mini-page".to_string()));
+        }
+        if !(0.0..=1.0).contains(&self.cache_probability) {
+            return Err(invalid(format!("cache_probability {} is not in [0, 1]", self.cache_probability)));
+        }
+        if let WalSyncPolicy::GroupCommit { max_batch: 0, .. } = self.wal_sync_policy {
+            return Err(invalid("wal_sync_policy group commit max_batch must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Builder for BfTreeOptions; `build` validates the result.
+#[derive(Debug, Clone)]
+pub struct BfTreeOptionsBuilder {
+    options: BfTreeOptions,
+}
+
+impl BfTreeOptionsBuilder {
+    pub fn leaf_page_size(mut self, size: usize) -> Self {
+        self.options.leaf_page_size = size;
+        self
+    }
+
+    pub fn inner_node_size(mut self, size: usize) -> Self {
+        self.options.inner_node_size = size;
+        self
+    }
+
+    pub fn mini_page_min_size(mut self, size: usize) -> Self {
+        self.options.mini_page_min_size = size;
+        self
+    }
+
+    pub fn mini_page_max_size(mut self, size: usize) -> Self {
+        self.options.mini_page_max_size = size;
+        self
+    }
+
+    pub fn buffer_pool_size(mut self, size: usize) -> Self {
+        self.options.buffer_pool_size = size;
+        self
+    }
+
+    pub fn cache_probability(mut self, probability: f64) -> Self {
+        self.options.cache_probability = probability;
+        self
+    }
+
+    pub fn wal_sync_policy(mut self, policy: WalSyncPolicy) -> Self {
+        self.options.wal_sync_policy = policy;
+        self
+    }
+
+    pub fn copy_on_write(mut self, enabled: bool) -> Self {
+        self.options.copy_on_write = enabled;
+        self
+    }
+
+    pub fn checkpoint_threshold(mut self, bytes: usize) -> Self {
+        self.options.checkpoint_threshold = bytes;
+        self
+    }
+
+    pub fn build(self) -> Result<BfTreeOptions> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+/// Settings of the background merge worker (see BackgroundMerger).
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackgroundMergeOptions {
+    pub mini_page_watermark: f64, // dirty mini-pages this share of mini_page_max_size or larger are merged
+    pub pool_watermark: f64,      // share of the buffer pool above which mini-pages are evicted from the tail
+    pub io_budget: usize,         // bytes of leaf pages read and written per second
+    pub interval: Duration,       // pause between passes
+}
+
+impl Default for BackgroundMergeOptions {
+    fn default() -> Self {
+        Self {
+            mini_page_watermark: 0.5,
+            pool_watermark: 0.75,
+            io_budget: 64 * 1024 * 1024,
+            interval: Duration::from_millis(10),
+        }
+    }
+}
+
+impl BackgroundMergeOptions {
+    /// Checks that both watermarks are in (0, 1] and that the budget and interval are not zero.
+    pub fn validate(&self) -> Result<()> {
+        for (name, watermark) in [("mini_page_watermark", self.mini_page_watermark), ("pool_watermark", self.pool_watermark)] {
+            if !(watermark > 0.0 && watermark <= 1.0) {
+                return Err(invalid(format!("{} {} is not in (0, 1]", name, watermark)));
+            }
+        }
+        if self.io_budget == 0 || self.interval.is_zero() {
+            return Err(invalid("background merge io_budget and interval must not be zero".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Sizes are powers of two between min and the largest u16 node_size.
+fn check_size(name: &str, size: usize, min: usize) -> Result<()> {
+    if !size.is_power_of_two() || size < min || size > 1 << 15 {
+        return Err(invalid(format!(
+            "{} must be a power of two between {} and {}, got {}",
+            name, min, 1 << 15, size
+        )));
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidOptions(message)
+}
@@ -0,0 +1,37 @@
+// src/crc32c.rs
+
+/// CRC-32C (Castagnoli) checksums of on-disk pages and WAL records.
+/// Table driven, one byte at a time; the table is built at compile time.
+const POLYNOMIAL: u32 = 0x82F6_3B78; // Castagnoli polynomial, bit reversed
+
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLYNOMIAL } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// The CRC-32C of data.
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_extend(0, data)
+}
+
+/// Extends crc, the CRC-32C of some bytes, to the CRC-32C of those bytes followed by data.
+pub fn crc32c_extend(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc = TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
@@ -0,0 +1,136 @@
+// src/epoch.rs
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+const PARTICIPANT_SLOTS: usize = 64;
+const UNPINNED: u64 = 0; // a pinned slot holds (epoch << 1) | 1
+const COLLECT_THRESHOLD: usize = 64; // retired objects that trigger a collection
+
+struct Retired {
+    epoch: u64,
+    _garbage: Box<dyn Send>, // only held to be dropped
+}
+
+/// Collector reclaims memory that lock-free readers may still be looking at.
+/// - A reader pins the collector for as long as it holds references into the
+///   structure; pinning announces the global epoch it started in.
+/// - A writer that unlinks an object retires it instead of dropping it; it is
+///   tagged with the global epoch at retirement.
+/// - The global epoch only advances once every pinned reader has announced the
+///   current one, so an object retired in epoch e is dropped once the global
+///   epoch reaches e + 2: every reader that could have reached it has unpinned.
+///
+/// Readers announce themselves in a fixed set of slots and never block writers;
+/// a reader waits only while all slots are taken.
+pub struct Collector {
+    epoch: AtomicU64,
+    slots: [AtomicU64; PARTICIPANT_SLOTS],
+    next_slot: AtomicUsize, // spreads readers over the slots
+    retired: Mutex<Vec<Retired>>,
+}
+
+/// Keeps the collector pinned: nothing retired after the guard was taken is
+/// dropped until the guard is.
+pub struct Guard<'a> {
+    collector: &'a Collector,
+    slot: usize,
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.collector.slots[self.slot].store(UNPINNED, Ordering::SeqCst);
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self {
+            epoch: AtomicU64::new(0),
+            slots: std::array::from_fn(|_| AtomicU64::new(UNPINNED)),
+            next_slot: AtomicUsize::new(0),
+            retired: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Pins the current epoch until the returned guard is dropped.
+    pub fn pin(&self) -> Guard<'_> {
+        loop {
+            let start = self.next_slot.fetch_add(1, Ordering::Relaxed);
+            for i in 0..PARTICIPANT_SLOTS {
+                let slot = (start + i) % PARTICIPANT_SLOTS;
+                let mut epoch = self.epoch.load(Ordering::SeqCst);
+                if self.slots[slot]
+                    .compare_exchange(UNPINNED, (epoch << 1) | 1, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    continue;
+                }
+                // The epoch may have advanced before the announcement was visible
+                loop {
+                    let current = self.epoch.load(Ordering::SeqCst);
+                    if current == epoch {
+                        return Guard { collector: self, slot };
+                    }
+                    epoch = current;
+                    self.slots[slot].store((epoch << 1) | 1, Ordering::SeqCst);
+                }
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// Drops garbage once no pinned reader can still reach it.
+    /// Must be called after garbage is unlinked from the structure.
+    pub fn retire<T: Send + 'static>(&self, garbage: Box<T>) {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        let pending = {
+            let mut retired = self.retired.lock().unwrap();
+            retired.push(Retired { epoch, _garbage: garbage });
+            retired.len()
+        };
+        if pending.is_multiple_of(COLLECT_THRESHOLD) {
+            self.collect();
+        }
+    }
+
+    /// Advances the epoch if every pinned reader has caught up, and drops the
+    /// garbage no reader can reach any more. Returns the number of objects dropped.
+    pub fn collect(&self) -> usize {
+        let epoch = self.try_advance();
+        let reclaimable: Vec<Retired> = {
+            let mut retired = self.retired.lock().unwrap();
+            let (reclaimable, pending) = retired.drain(..).partition(|r| r.epoch + 2 <= epoch);
+            *retired = pending;
+            reclaimable
+        };
+        // Dropped outside the lock
+        reclaimable.len()
+    }
+
+    /// Number of retired objects not dropped yet.
+    pub fn pending(&self) -> usize {
+        self.retired.lock().unwrap().len()
+    }
+
+    /// Returns the global epoch after trying to advance it by one.
+    fn try_advance(&self) -> u64 {
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        for slot in &self.slots {
+            let announced = slot.load(Ordering::SeqCst);
+            if announced != UNPINNED && announced >> 1 != epoch {
+                return epoch;
+            }
+        }
+        match self.epoch.compare_exchange(epoch, epoch + 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => epoch + 1,
+            Err(current) => current,
+        }
+    }
+}
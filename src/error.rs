@@ -0,0 +1,62 @@
+// src/error.rs
+
+use std::fmt;
+use std::io;
+
+/// Errors returned by BfTree operations.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing the page file failed.
+    Io(io::Error),
+    /// Bytes read from disk do not describe a valid header or page.
+    Corruption(String),
+    /// A record (key plus value) is too large to be buffered in a mini-page,
+    /// its key is longer than MiniPage::max_key_size, or its value is longer
+    /// than KVMeta::MAX_SIZE.
+    KeyTooLarge { record_size: usize, max_size: usize },
+    /// The file was written in a newer on-disk format version than this build supports.
+    UnsupportedVersion { version: u32, supported: u32 },
+    /// BfTreeOptions that do not describe a usable tree.
+    InvalidOptions(String),
+    /// An in-memory structure of the tree is inconsistent, e.g. a page ID
+    /// routed to by an inner node is missing from the mapping table.
+    InvalidState(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Corruption(message) => write!(f, "corruption: {}", message),
+            Error::KeyTooLarge { record_size, max_size } => write!(
+                f,
+                "record of {} bytes does not fit a {} byte mini-page",
+                record_size, max_size
+            ),
+            Error::UnsupportedVersion { version, supported } => write!(
+                f,
+                "file format version {} is newer than the supported version {}",
+                version, supported
+            ),
+            Error::InvalidOptions(message) => write!(f, "invalid options: {}", message),
+            Error::InvalidState(message) => write!(f, "invalid tree state: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
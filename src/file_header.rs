@@ -0,0 +1,188 @@
+// src/file_header.rs
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Cursor, Read, Write};
+
+use crate::checkpoint::{CheckpointPointer, CHECKPOINT_POINTER_SIZE};
+use crate::config::BfTreeOptions;
+use crate::crc32c::crc32c;
+use crate::error::{Error, Result};
+
+pub const FILE_MAGIC: [u8; 8] = *b"BFTREE\0\0";
+pub const FILE_HEADER_SIZE: usize = 128; // bytes of one header slot
+pub const FILE_HEADER_SLOTS: usize = 2;  // copies at the start of the header page, written in turn
+pub const FORMAT_VERSION: u32 = 1;       // on-disk format written by this build
+
+const CHECKSUM_OFFSET: usize = FILE_HEADER_SIZE - 4; // CRC-32C of the bytes before it
+
+/// FileHeader is the superblock of the storage file: page 0 holds two copies
+/// of it, FILE_HEADER_SIZE bytes each. It identifies the file and its format
+/// version, records the sizes the tree was created with and points at the two
+/// most recent checkpoints, which locate the root and every other page.
+///
+/// An update bumps the generation and overwrites the slot of the older copy
+/// (generation % 2), so a torn write leaves the other one intact; the valid
+/// copy with the highest generation is current.
+///
+/// Layout: magic, format version, generation, the sizes, the checkpoint
+/// slots, zero padding, then a CRC-32C of the rest.
+/// The magic and version come first in every format version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileHeader {
+    pub format_version: u32,
+    pub generation: u64,
+    pub leaf_page_size: u32,
+    pub inner_node_size: u32,
+    pub mini_page_min_size: u32,
+    pub mini_page_max_size: u32,
+    pub checkpoints: [CheckpointPointer; 2],
+}
+
+impl FileHeader {
+    /// Captures the persisted (layout) part of the options.
+    pub fn from_options(options: &BfTreeOptions) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            generation: 0,
+            leaf_page_size: options.leaf_page_size as u32,
+            inner_node_size: options.inner_node_size as u32,
+            mini_page_min_size: options.mini_page_min_size as u32,
+            mini_page_max_size: options.mini_page_max_size as u32,
+            checkpoints: [CheckpointPointer::default(); 2],
+        }
+    }
+
+    /// Checkpoint slots that hold a checkpoint, newest first.
+    pub fn checkpoints_newest_first(&self) -> Vec<CheckpointPointer> {
+        let mut checkpoints: Vec<_> = self.checkpoints.iter().copied().filter(|c| c.len > 0).collect();
+        checkpoints.sort_by_key(|c| std::cmp::Reverse(c.sequence));
+        checkpoints
+    }
+
+    /// Sequence number for the next checkpoint.
+    pub fn next_checkpoint_sequence(&self) -> u64 {
+        self.checkpoints.iter().map(|c| c.sequence).max().unwrap_or(0) + 1
+    }
+
+    /// Index of the oldest checkpoint slot, which the next checkpoint replaces.
+    fn oldest_slot(&self) -> usize {
+        if self.checkpoints[0].sequence <= self.checkpoints[1].sequence { 0 } else { 1 }
+    }
+
+    /// The checkpoint with_checkpoint drops from the header; its len is zero if the slot is empty.
+    pub fn replaced_checkpoint(&self) -> CheckpointPointer {
+        self.checkpoints[self.oldest_slot()]
+    }
+
+    /// Returns a header that points at checkpoint in place of the oldest slot.
+    pub fn with_checkpoint(&self, checkpoint: CheckpointPointer) -> Self {
+        let mut header = self.clone();
+        header.checkpoints[self.oldest_slot()] = checkpoint;
+        header
+    }
+
+    /// Returns options with the persisted sizes and the caller's runtime settings.
+    pub fn apply_to(&self, options: &BfTreeOptions) -> BfTreeOptions {
+        BfTreeOptions {
+            leaf_page_size: self.leaf_page_size as usize,
+            inner_node_size: self.inner_node_size as usize,
+            mini_page_min_size: self.mini_page_min_size as usize,
+            mini_page_max_size: self.mini_page_max_size as usize,
+            ..options.clone()
+        }
+    }
+
+    /// Byte offset of the slot this header is written to.
+    pub fn slot_offset(&self) -> u64 {
+        (self.generation % FILE_HEADER_SLOTS as u64) * FILE_HEADER_SIZE as u64
+    }
+
+    /// Serializes the header into one slot.
+    pub fn serialize(&self) -> Result<[u8; FILE_HEADER_SIZE]> {
+        let mut buf = [0u8; FILE_HEADER_SIZE];
+        let mut cursor = Cursor::new(&mut buf[..]);
+
+        cursor.write_all(&FILE_MAGIC)?;
+        cursor.write_u32::<LittleEndian>(FORMAT_VERSION)?;
+        cursor.write_u64::<LittleEndian>(self.generation)?;
+        cursor.write_u32::<LittleEndian>(self.leaf_page_size)?;
+        cursor.write_u32::<LittleEndian>(self.inner_node_size)?;
+        cursor.write_u32::<LittleEndian>(self.mini_page_min_size)?;
+        cursor.write_u32::<LittleEndian>(self.mini_page_max_size)?;
+        for checkpoint in &self.checkpoints {
+            checkpoint.serialize_into(&mut cursor)?;
+        }
+
+        let checksum = crc32c(&buf[..CHECKSUM_OFFSET]);
+        buf[CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+        Ok(buf)
+    }
+
+    /// Deserializes one header slot, rejecting files that are not bftree files
+    /// and slots whose checksum does not match. The checksum is verified first,
+    /// so a damaged slot is never mistaken for another format version; a valid
+    /// slot of a newer format version fails with Error::UnsupportedVersion.
+    pub fn deserialize(buf: &[u8]) -> Result<Self> {
+        if buf.len() < FILE_HEADER_SIZE || buf[..8] != FILE_MAGIC {
+            return Err(Error::Corruption("not a bftree file (bad magic)".to_string()));
+        }
+        let stored = u32::from_le_bytes(buf[CHECKSUM_OFFSET..FILE_HEADER_SIZE].try_into().unwrap());
+        if stored != crc32c(&buf[..CHECKSUM_OFFSET]) {
+            return Err(Error::Corruption("file header checksum does not match".to_string()));
+        }
+        let mut cursor = Cursor::new(&buf[8..]);
+
+        let format_version = cursor.read_u32::<LittleEndian>()?;
+        if format_version > FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion { version: format_version, supported: FORMAT_VERSION });
+        }
+        if format_version != FORMAT_VERSION {
+            return Err(Error::Corruption(format!("file header has format version {}", format_version)));
+        }
+
+        let mut header = Self {
+            format_version,
+            generation: cursor.read_u64::<LittleEndian>()?,
+            leaf_page_size: cursor.read_u32::<LittleEndian>()?,
+            inner_node_size: cursor.read_u32::<LittleEndian>()?,
+            mini_page_min_size: cursor.read_u32::<LittleEndian>()?,
+            mini_page_max_size: cursor.read_u32::<LittleEndian>()?,
+            checkpoints: [CheckpointPointer::default(); 2],
+        };
+        for checkpoint in header.checkpoints.iter_mut() {
+            let mut pointer = [0u8; CHECKPOINT_POINTER_SIZE];
+            cursor.read_exact(&mut pointer)?;
+            *checkpoint = CheckpointPointer::deserialize(&pointer)?;
+        }
+
+        // Persisted sizes must still make sense before anything is laid out with them
+        header.apply_to(&BfTreeOptions::default()).validate().map_err(|e| {
+            Error::Corruption(format!("file header: {}", e))
+        })?;
+        Ok(header)
+    }
+
+    /// Picks the current header from the header slots at the start of the
+    /// header page: the valid one with the highest generation.
+    /// A valid slot of a newer format version fails the whole file, as it may be the current one.
+    pub fn read_slots(buf: &[u8]) -> Result<Self> {
+        let mut current: Option<Self> = None;
+        let mut first_error = None;
+        for slot in buf.chunks_exact(FILE_HEADER_SIZE).take(FILE_HEADER_SLOTS) {
+            match Self::deserialize(slot) {
+                Ok(header) => {
+                    if current.as_ref().is_none_or(|c| header.generation > c.generation) {
+                        current = Some(header);
+                    }
+                }
+                Err(e @ Error::UnsupportedVersion { .. }) => return Err(e),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        current.ok_or_else(|| {
+            first_error.unwrap_or_else(|| Error::Corruption("file is too short for a bftree header".to_string()))
+        })
+    }
+}
@@ -1,5 +1,17 @@
 // src/inner_node.rs
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::epoch::Collector;
+use crate::error::{Error, Result};
+use crate::page::Fences;
+use crate::page_id_allocator::PageIdAllocator;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct InnerNode {
     pub keys: Vec<Vec<u8>>, // Sorted separator keys
     pub children: Vec<u64>, // Child page IDs 
@@ -14,41 +26,491 @@ impl InnerNode {
         }
     }
 
+    /// Index of the child covering key: child i holds keys in [keys[i - 1], keys[i]).
+    pub fn child_index(&self, key: &[u8]) -> usize {
+        self.keys.partition_point(|k| k.as_slice() <= key)
+    }
+
     /// Finds the child page ID for the given key using binary search.
     ///
     /// Returns Some(child_page_id) if found, or None if invalid tree state.
     pub fn find_child_page_id(&self, key: &[u8]) -> Option<u64> {
-        if self.keys.is_empty() {
-            // Edge case: no keys, single child only
-            return self.children.first().copied();
+        self.children.get(self.child_index(key)).copied()
+    }
+
+    /// Inserts a separator key and child pointer at the appropriate position.
+    /// For tree building and splits (basic version).
+    pub fn insert(&mut self, key: Vec<u8>, child_page_id: u64) {
+        let pos = self.keys.binary_search(&key).unwrap_or_else(|e| e);
+        self.keys.insert(pos, key);
+        self.children.insert(pos + 1, child_page_id);
+    }
+
+    /// Size of the node when laid out in an inner node page:
+    /// a 4-byte header, a 2-byte length prefix per key, and 8 bytes per child.
+    pub fn byte_size(&self) -> usize {
+        let key_bytes: usize = self.keys.iter().map(|k| 2 + k.len()).sum();
+        4 + key_bytes + self.children.len() * 8
+    }
+
+    /// True if the node no longer fits in inner_node_size bytes and must be split.
+    pub fn is_overfull(&self, inner_node_size: usize) -> bool {
+        self.byte_size() > inner_node_size
+    }
+
+    /// Splits the node in half.
+    /// The middle key moves up to the parent; returns (separator, right node).
+    pub fn split(&mut self) -> (Vec<u8>, InnerNode) {
+        let mid = self.keys.len() / 2;
+
+        let right = InnerNode {
+            keys: self.keys.split_off(mid + 1),
+            children: self.children.split_off(mid + 1),
+        };
+        let separator = self.keys.pop().unwrap();
+
+        (separator, right)
+    }
+
+    /// Appends the node to buf in the layout measured by byte_size:
+    /// key count u16, child count u16, length-prefixed keys, then child page IDs.
+    pub fn serialize_into(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.write_u16::<LittleEndian>(self.keys.len() as u16)?;
+        buf.write_u16::<LittleEndian>(self.children.len() as u16)?;
+        for key in &self.keys {
+            buf.write_u16::<LittleEndian>(key.len() as u16)?;
+            buf.extend_from_slice(key);
+        }
+        for &child in &self.children {
+            buf.write_u64::<LittleEndian>(child)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a node written by serialize_into from the start of buf.
+    /// Returns the node and the number of bytes it occupied.
+    pub fn deserialize(buf: &[u8]) -> Result<(Self, usize)> {
+        let truncated = |_| Error::Corruption("inner node is truncated".to_string());
+        let mut cursor = Cursor::new(buf);
+
+        let key_count = cursor.read_u16::<LittleEndian>().map_err(truncated)? as usize;
+        let child_count = cursor.read_u16::<LittleEndian>().map_err(truncated)? as usize;
+        if child_count != key_count + 1 && !(key_count == 0 && child_count == 0) {
+            return Err(Error::Corruption(format!(
+                "inner node has {} keys but {} children",
+                key_count, child_count
+            )));
+        }
+
+        let mut keys = Vec::with_capacity(key_count);
+        for _ in 0..key_count {
+            let len = cursor.read_u16::<LittleEndian>().map_err(truncated)? as usize;
+            let mut key = vec![0u8; len];
+            cursor.read_exact(&mut key).map_err(truncated)?;
+            keys.push(key);
+        }
+        if keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(Error::Corruption("inner node keys are not sorted".to_string()));
+        }
+
+        let mut children = Vec::with_capacity(child_count);
+        for _ in 0..child_count {
+            children.push(cursor.read_u64::<LittleEndian>().map_err(truncated)?);
         }
 
-        let mut left = 0;
-        let mut right = self.keys.len();
+        Ok((Self { keys, children }, cursor.position() as usize))
+    }
+}
+
+/// A neighbouring leaf: (page ID, separator between it and the leaf).
+pub type Neighbour = (u64, Vec<u8>);
+
+/// One immutable version of an inner node, with direct links to the slots of
+/// its child inner nodes (None for leaf children), so readers descend
+/// without looking page IDs up.
+#[derive(Clone)]
+struct NodeImage {
+    node: InnerNode,
+    child_slots: Vec<Option<Arc<InnerSlot>>>, // parallel to node.children
+}
+
+impl NodeImage {
+    /// Mirrors InnerNode::insert, keeping child_slots in step.
+    fn insert(&mut self, key: Vec<u8>, child_page_id: u64, child_slot: Option<Arc<InnerSlot>>) {
+        let pos = self.node.keys.binary_search(&key).unwrap_or_else(|e| e);
+        self.node.insert(key, child_page_id);
+        self.child_slots.insert(pos + 1, child_slot);
+    }
+
+    /// Mirrors InnerNode::split, keeping child_slots in step.
+    fn split(&mut self) -> (Vec<u8>, NodeImage) {
+        let mid = self.node.keys.len() / 2;
+        let (separator, right) = self.node.split();
+        let right_slots = self.child_slots.split_off(mid + 1);
+        (separator, NodeImage { node: right, child_slots: right_slots })
+    }
+}
+
+/// Holds one inner node for optimistic lock coupling:
+/// - version is even while the node is unlatched and odd while a writer holds
+///   it; every write latch bumps it twice
+/// - the node image is never modified in place, a writer publishes a new one
+///
+/// Readers never block: they note the version, read the image, and restart
+/// if the version changed in the meantime.
+struct InnerSlot {
+    version: AtomicU64,
+    image: AtomicPtr<NodeImage>,
+}
+
+impl InnerSlot {
+    fn new(image: NodeImage) -> Self {
+        Self {
+            version: AtomicU64::new(0),
+            image: AtomicPtr::new(Box::into_raw(Box::new(image))),
+        }
+    }
 
-        while left < right {
-            let mid = (left + right) / 2;
-            match key.cmp(&self.keys[mid]) {
-                std::cmp::Ordering::Less => right = mid,
-                std::cmp::Ordering::Equal => return self.children.get(mid + 1).copied(),
-                std::cmp::Ordering::Greater => left = mid + 1,
+    /// The current version, or None while a writer holds the node.
+    fn read_version(&self) -> Option<u64> {
+        let version = self.version.load(Ordering::SeqCst);
+        if version % 2 == 1 {
+            return None;
+        }
+        Some(version)
+    }
+
+    /// True if the node did not change since read_version returned version.
+    fn validate(&self, version: u64) -> bool {
+        self.version.load(Ordering::SeqCst) == version
+    }
+
+    fn image(&self) -> &NodeImage {
+        // SAFETY: an image is only freed when its slot is dropped or, once
+        // replaced, after every reader pinned in InnerTree::collector unpinned.
+        unsafe { &*self.image.load(Ordering::SeqCst) }
+    }
+
+    /// Latches the node exclusively; readers restart until unlatch.
+    fn latch(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn unlatch(&self) {
+        self.version.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Publishes a new image and returns the replaced one. Must be latched.
+    fn publish(&self, image: NodeImage) -> Box<NodeImage> {
+        let old = self.image.swap(Box::into_raw(Box::new(image)), Ordering::SeqCst);
+        // SAFETY: every image pointer comes from Box::into_raw and is swapped out once
+        unsafe { Box::from_raw(old) }
+    }
+}
+
+impl Drop for InnerSlot {
+    fn drop(&mut self) {
+        // SAFETY: the current image was created by Box::into_raw and is owned by the slot
+        drop(unsafe { Box::from_raw(*self.image.get_mut()) });
+    }
+}
+
+/// InnerTree holds every pinned InnerNode of a BfTree: the root under page ID 0
+/// and the rest by page ID.
+///
+/// Lookups use optimistic lock coupling: each node carries a version counter,
+/// and a reader validates a parent's version after stepping to the child, so
+/// it never takes a lock and restarts from the root on conflict. Writers are
+/// serialized by the nodes lock and latch every node they change before
+/// publishing any of the new versions. Replaced images are retired to the
+/// collector, which frees them once no lookup can still be reading them.
+pub struct InnerTree {
+    root: InnerSlot,
+    nodes: Mutex<HashMap<u64, Arc<InnerSlot>>>, // non-root nodes by page ID; held by writers
+    collector: Collector,                        // pinned by lookups, frees replaced images
+}
+
+impl Default for InnerTree {
+    fn default() -> Self {
+        Self::from_nodes([(0, InnerNode::new())])
+    }
+}
+
+impl InnerTree {
+    /// Builds the tree from (page ID, InnerNode) pairs; page ID 0 is the root.
+    pub fn from_nodes(nodes: impl IntoIterator<Item = (u64, InnerNode)>) -> Self {
+        let nodes: HashMap<u64, InnerNode> = nodes.into_iter().collect();
+        let mut slots = HashMap::new();
+        for &page_id in nodes.keys() {
+            if page_id != 0 {
+                Self::build_slot(page_id, &nodes, &mut slots);
             }
         }
 
-        // If key < all separator keys ➔ return first child.
-        // If key > all separator keys ➔ return last child.
-        if left == 0 {
-            self.children.first().copied()
-        } else {
-            self.children.get(left).copied()
+        let root = nodes.get(&0).cloned().unwrap_or_default();
+        Self {
+            root: InnerSlot::new(Self::image_of(root, &nodes, &mut slots)),
+            nodes: Mutex::new(slots),
+            collector: Collector::new(),
         }
     }
 
-    /// Inserts a separator key and child pointer at the appropriate position.
-    /// For tree building and splits (basic version).
-    pub fn insert(&mut self, key: Vec<u8>, child_page_id: u64) {
-        let pos = self.keys.binary_search(&key).unwrap_or_else(|e| e);
-        self.keys.insert(pos, key);
-        self.children.insert(pos + 1, child_page_id);
+    /// Creates the slot of a non-root node after the slots of its inner children.
+    fn build_slot(page_id: u64, nodes: &HashMap<u64, InnerNode>, slots: &mut HashMap<u64, Arc<InnerSlot>>) -> Arc<InnerSlot> {
+        if let Some(slot) = slots.get(&page_id) {
+            return Arc::clone(slot);
+        }
+        let image = Self::image_of(nodes[&page_id].clone(), nodes, slots);
+        let slot = Arc::new(InnerSlot::new(image));
+        slots.insert(page_id, Arc::clone(&slot));
+        slot
+    }
+
+    fn image_of(node: InnerNode, nodes: &HashMap<u64, InnerNode>, slots: &mut HashMap<u64, Arc<InnerSlot>>) -> NodeImage {
+        let child_slots = node
+            .children
+            .iter()
+            .map(|&child| match child {
+                0 => None,
+                _ if nodes.contains_key(&child) => Some(Self::build_slot(child, nodes, slots)),
+                _ => None,
+            })
+            .collect();
+        NodeImage { node, child_slots }
+    }
+
+    /// Every (page ID, InnerNode) pair in page ID order, root first.
+    pub fn to_nodes(&self) -> Vec<(u64, InnerNode)> {
+        let slots = self.nodes.lock().unwrap();
+        let mut nodes = vec![(0, self.root.image().node.clone())];
+        nodes.extend(slots.iter().map(|(&page_id, slot)| (page_id, slot.image().node.clone())));
+        nodes.sort_by_key(|(page_id, _)| *page_id);
+        nodes
+    }
+
+    /// Number of inner nodes, including the root.
+    pub fn node_count(&self) -> usize {
+        self.nodes.lock().unwrap().len() + 1
+    }
+
+    /// A copy of the inner node with the given page ID.
+    ///
+    /// In Bf-Tree, inner nodes are pinned in memory and referenced directly by page_id.
+    pub fn get(&self, page_id: u64) -> Option<InnerNode> {
+        if page_id == 0 {
+            return Some(self.root.image().node.clone());
+        }
+        let slots = self.nodes.lock().unwrap();
+        slots.get(&page_id).map(|slot| slot.image().node.clone())
+    }
+
+    /// Routes key to its leaf page ID.
+    /// Also returns the leaf's fences: the separators either side of it on the
+    /// way down, which match the fence keys of the leaf page.
+    pub fn route(&self, key: &[u8]) -> Result<(u64, Fences)> {
+        let _guard = self.collector.pin();
+        'restart: loop {
+            let mut slot = &self.root;
+            let mut version = match slot.read_version() {
+                Some(version) => version,
+                None => {
+                    std::hint::spin_loop();
+                    continue 'restart;
+                }
+            };
+            let mut low_key: &[u8] = &[];
+            let mut high_key = None;
+
+            loop {
+                let image = slot.image();
+                let idx = image.node.child_index(key);
+                let child_page_id = image.node.children.get(idx).copied();
+                // Deeper separators are tighter bounds than the ones above them
+                if idx > 0 {
+                    low_key = &image.node.keys[idx - 1];
+                }
+                if let Some(separator) = image.node.keys.get(idx) {
+                    high_key = Some(separator.as_slice());
+                }
+                let child_slot = image.child_slots.get(idx).and_then(|child_slot| child_slot.as_deref());
+                if !slot.validate(version) {
+                    continue 'restart;
+                }
+
+                let child_page_id = child_page_id.ok_or_else(|| {
+                    Error::InvalidState(format!("no child page ID found for key {:?}", key))
+                })?;
+                let child_slot = match child_slot {
+                    Some(child_slot) => child_slot,
+                    None => return Ok((child_page_id, Fences::new(low_key, high_key))),
+                };
+
+                // Couple: the parent must still be unchanged once the child's version is known
+                version = match child_slot.read_version() {
+                    Some(child_version) if slot.validate(version) => child_version,
+                    _ => continue 'restart,
+                };
+                slot = child_slot;
+            }
+        }
+    }
+
+    /// Returns the page IDs and slots of the inner nodes visited from the root
+    /// to the last-level inner node for key. Only called by writers, which
+    /// hold the nodes lock, so the images are stable.
+    fn path(&self, key: &[u8]) -> Vec<(u64, &InnerSlot)> {
+        let mut path = vec![(0, &self.root)];
+        let mut slot = &self.root;
+
+        loop {
+            let image = slot.image();
+            let idx = image.node.child_index(key);
+            match (image.node.children.get(idx), image.child_slots.get(idx)) {
+                (Some(&child_page_id), Some(Some(child_slot))) => {
+                    slot = child_slot;
+                    path.push((child_page_id, slot));
+                }
+                _ => break,
+            }
+        }
+        path
+    }
+
+    /// The leaf children either side of the leaf key routes to, within its
+    /// last-level inner node: (leaf page ID, left neighbour, right neighbour).
+    /// Neighbours under another parent are not returned.
+    pub fn leaf_neighbours(&self, key: &[u8]) -> Result<(u64, Option<Neighbour>, Option<Neighbour>)> {
+        let _slots = self.nodes.lock().unwrap();
+        let &(_, parent) = self.path(key).last().unwrap();
+        let node = &parent.image().node;
+        let idx = node.child_index(key);
+        let page_id = node.children.get(idx).copied().ok_or_else(|| {
+            Error::InvalidState(format!("no child page ID found for key {:?}", key))
+        })?;
+        let left = (idx > 0).then(|| (node.children[idx - 1], node.keys[idx - 1].clone()));
+        let right = node.children.get(idx + 1).map(|&child| (child, node.keys[idx].clone()));
+        Ok((page_id, left, right))
+    }
+
+    /// Removes separator from its parent together with removed_child, one of
+    /// the two leaves it separates, once that leaf was merged into the other.
+    /// Returns false, changing nothing, if separator no longer sits next to
+    /// removed_child in a last-level inner node (e.g. an inner split moved it up).
+    pub fn remove_separator(&self, separator: &[u8], removed_child: u64) -> bool {
+        self.update_parent(separator, |image, pos| {
+            let child = match image.node.children[pos..=pos + 1].iter().position(|&c| c == removed_child) {
+                Some(side) => pos + side,
+                None => return false,
+            };
+            image.node.keys.remove(pos);
+            image.node.children.remove(child);
+            image.child_slots.remove(child);
+            true
+        })
+    }
+
+    /// Replaces separator in its parent with new_separator, once records
+    /// moved between the two leaves it separates. new_separator must lie
+    /// between the neighbouring separators.
+    /// Returns false, changing nothing, if separator is no longer in a
+    /// last-level inner node or the node would overflow inner_node_size.
+    pub fn replace_separator(&self, separator: &[u8], new_separator: Vec<u8>, inner_node_size: usize) -> bool {
+        self.update_parent(separator, |image, pos| {
+            image.node.keys[pos] = new_separator;
+            !image.node.is_overfull(inner_node_size)
+        })
+    }
+
+    /// Runs update on a copy of the last-level inner node holding separator
+    /// (with its position in keys) and publishes the result if update returns true.
+    fn update_parent(&self, separator: &[u8], update: impl FnOnce(&mut NodeImage, usize) -> bool) -> bool {
+        let _slots = self.nodes.lock().unwrap();
+        let &(_, slot) = self.path(separator).last().unwrap();
+        let mut image = slot.image().clone();
+        let pos = match image.node.keys.binary_search_by(|k| k.as_slice().cmp(separator)) {
+            Ok(pos) if image.child_slots[pos..=pos + 1].iter().all(Option::is_none) => pos,
+            _ => return false,
+        };
+        if !update(&mut image, pos) {
+            return false;
+        }
+
+        slot.latch();
+        let replaced = slot.publish(image);
+        slot.unlatch();
+        self.collector.retire(replaced);
+        true
+    }
+
+    /// Inserts separator → child_page_id into the last-level inner node for
+    /// separator, splitting inner nodes upward while they overflow inner_node_size.
+    /// A root split grows the tree by one level; the root keeps page ID 0.
+    ///
+    /// The separator must still route to the leaf being split, so the path
+    /// ends at the parent to update: a split into several leaves inserts its
+    /// separators right to left.
+    pub fn insert_separator(
+        &self,
+        separator: Vec<u8>,
+        child_page_id: u64,
+        inner_node_size: usize,
+        page_id_allocator: &PageIdAllocator,
+    ) -> Result<()> {
+        let mut slots = self.nodes.lock().unwrap();
+        let path = self.path(&separator);
+
+        // Build every new image first; nothing is visible to readers yet
+        let mut updates: Vec<(&InnerSlot, NodeImage)> = Vec::new();
+        let mut separator = separator;
+        let mut child_page_id = child_page_id;
+        let mut child_slot = None;
+
+        for &(node_id, slot) in path.iter().rev() {
+            let mut image = slot.image().clone();
+            image.insert(separator, child_page_id, child_slot);
+            if !image.node.is_overfull(inner_node_size) {
+                updates.push((slot, image));
+                break;
+            }
+
+            let (up_separator, right) = image.split();
+            let right_id = page_id_allocator.allocate() as u64;
+            let right_slot = Arc::new(InnerSlot::new(right));
+            slots.insert(right_id, Arc::clone(&right_slot));
+
+            if node_id == 0 {
+                // Move the old root's left half to a new page and grow a new root
+                let left_id = page_id_allocator.allocate() as u64;
+                let left_slot = Arc::new(InnerSlot::new(image));
+                slots.insert(left_id, Arc::clone(&left_slot));
+
+                let root = InnerNode { keys: vec![up_separator], children: vec![left_id, right_id] };
+                updates.push((slot, NodeImage { node: root, child_slots: vec![Some(left_slot), Some(right_slot)] }));
+                break;
+            }
+
+            updates.push((slot, image));
+            separator = up_separator;
+            child_page_id = right_id;
+            child_slot = Some(right_slot);
+        }
+
+        // Latch every changed node before publishing, so a reader that saw
+        // any new image fails validation on the nodes above it
+        let latched: Vec<&InnerSlot> = updates.iter().map(|(slot, _)| *slot).collect();
+        for slot in latched.iter().rev() {
+            slot.latch();
+        }
+        let mut replaced = Vec::new();
+        for (slot, image) in updates {
+            replaced.push(slot.publish(image));
+        }
+        for slot in latched {
+            slot.unlatch();
+        }
+        for image in replaced {
+            self.collector.retire(image);
+        }
+        Ok(())
     }
 }
@@ -1,10 +1,14 @@
 // src/leaf_page.rs
 
-use std::fs::OpenOptions;
-use std::io::{Seek, SeekFrom};
+use crate::page::{Fences, Page, NodeMeta, KVMeta, PageType, RecordType};
+use crate::config::{LEAF_PAGE_SIZE, LEAF_UNDERFLOW_DIVISOR};
+use crate::crc32c::crc32c_extend;
+use crate::error::{Error, Result};
+use crate::storage::PageFile;
 
-use crate::page::{Page, NodeMeta, PageType};
-use crate::config::{LEAF_PAGE_SIZE};
+const CHECKSUM_FLAG: u8 = 0x04; // NodeMeta flags bit of pages that carry a checksum
+const CHECKSUM_START: usize = 6; // the checksum takes 4 bytes of the NodeMeta leaf field,
+const CHECKSUM_END: usize = 10;  // which leaf pages do not use
 
 #[derive(Clone)]
 pub struct LeafPage {
@@ -12,17 +16,15 @@ pub struct LeafPage {
 }
 
 impl LeafPage {
-    /// Loads a LeafPage from disk at given offset.
-    pub fn load_from_disk(disk_offset: u64) -> Self {
-        // In real implementation:
-        // 1. Seek to disk_offset in file.
-        // 2. Read PAGE_SIZE bytes.
-        // 3. Parse NodeMeta, KVMeta array, and data block.
-        //
-        // Here is a placeholder with unimplemented:
+    /// Creates a new empty LeafPage of the default size.
+    pub fn new() -> Self {
+        Self::with_size(LEAF_PAGE_SIZE)
+    }
 
+    /// Creates a new empty LeafPage of page_size bytes.
+    pub fn with_size(page_size: usize) -> Self {
         let node_meta = NodeMeta::new(
-            LEAF_PAGE_SIZE as u16, // default leaf page size
+            page_size as u16,
             PageType::LeafPage,
             false,
             0,
@@ -34,30 +36,82 @@ impl LeafPage {
         Self { page }
     }
 
-    /// Loads a LeafPage from disk at the given offset.
-    pub fn load_from_disk(disk_offset: u64) -> Self {
-        let mut file = File::open("storage.bftree").expect("Failed to open file");
-        file.seek(SeekFrom::Start(disk_offset)).expect("Failed to seek");
+    /// Loads a LeafPage from the page file at the given offset.
+    pub fn load_from_disk(storage: &PageFile, disk_offset: u64) -> Result<Self> {
+        let buffer = storage.read_page(disk_offset)?;
+        Self::from_bytes(&buffer).map_err(|e| match e {
+            Error::Corruption(message) => {
+                Error::Corruption(format!("leaf page at offset {}: {}", disk_offset, message))
+            }
+            e => e,
+        })
+    }
+
+    /// Parses a LeafPage from a page-sized buffer.
+    /// Fails with Error::Corruption, rather than panicking, if the page carries
+    /// no checksum, the checksum does not match or the page is malformed: the
+    /// NodeMeta or a KVMeta points outside the buffer, or the records are not in key order.
+    pub fn from_bytes(buffer: &[u8]) -> Result<Self> {
+        if buffer.len() < 12 {
+            return Err(Error::Corruption(format!("{} bytes is too short for a page", buffer.len())));
+        }
 
-        let mut buffer = vec![0u8; LEAF_PAGE_SIZE];
-        file.read_exact(&mut buffer).expect("Failed to read full page");
+        if buffer[2] & CHECKSUM_FLAG == 0 {
+            return Err(Error::Corruption("page carries no checksum".to_string()));
+        }
+        let stored = u32::from_le_bytes(buffer[CHECKSUM_START..CHECKSUM_END].try_into().unwrap());
+        let computed = Self::checksum(buffer);
+        if stored != computed {
+            return Err(Error::Corruption(format!(
+                "checksum {:#010x} does not match the page contents ({:#010x})",
+                stored, computed
+            )));
+        }
 
-        // 1. Deserialize NodeMeta (first 12 bytes)
+        // 1. Deserialize NodeMeta (first 12 bytes); its leaf field holds the checksum
         let meta_bytes: [u8; 12] = buffer[0..12].try_into().unwrap();
-        let node_meta = NodeMeta::deserialize(&meta_bytes).unwrap();
+        let mut node_meta = NodeMeta::deserialize(&meta_bytes)?;
+        node_meta.leaf = 0;
+        if node_meta.node_size as usize != buffer.len() {
+            return Err(Error::Corruption(format!(
+                "node_size {} does not match the {} byte page",
+                node_meta.node_size,
+                buffer.len()
+            )));
+        }
+        if node_meta.page_type {
+            return Err(Error::Corruption("page is flagged as a mini-page".to_string()));
+        }
 
         // 2. Deserialize KVMetas
-        let mut kv_metas = Vec::new();
-        let mut offset = 12;
-        for _ in 0..node_meta.record_count {
-            let kv_bytes: [u8; 8] = buffer[offset..offset + 8].try_into().unwrap();
-            let kv = KVMeta::deserialize(&kv_bytes).unwrap();
-            kv_metas.push(kv);
-            offset += 8;
+        let data_start = 12 + node_meta.record_count as usize * 8;
+        if data_start > buffer.len() {
+            return Err(Error::Corruption(format!(
+                "{} records do not fit the page",
+                node_meta.record_count
+            )));
+        }
+        let mut kv_metas = Vec::with_capacity(node_meta.record_count as usize);
+        for kv_bytes in buffer[12..data_start].chunks_exact(8) {
+            kv_metas.push(KVMeta::deserialize(kv_bytes.try_into().unwrap())?);
+        }
+
+        // 3. Data block runs up to the end of the furthest record (rest is padding)
+        let data_len = kv_metas
+            .iter()
+            .map(|kv| kv.offset as usize + kv.key_size as usize + kv.value_size as usize)
+            .max()
+            .unwrap_or(0);
+        if data_start + data_len > buffer.len() {
+            return Err(Error::Corruption("record data runs past the end of the page".to_string()));
         }
+        let data = buffer[data_start..data_start + data_len].to_vec();
 
-        // 3. Remaining bytes are the data block
-        let data = buffer[offset..].to_vec();
+        // Fence records come first, as a low and high pair
+        let fence_count = kv_metas.iter().take_while(|kv| kv.is_fence).count();
+        if (fence_count != 0 && fence_count != 2) || kv_metas[fence_count..].iter().any(|kv| kv.is_fence) {
+            return Err(Error::Corruption("fence records are misplaced".to_string()));
+        }
 
         let page = Page {
             node_meta,
@@ -65,7 +119,16 @@ impl LeafPage {
             data,
         };
 
-        Self { page }
+        // Binary search relies on strictly increasing keys within the fences
+        let records = page.record_range();
+        if records.clone().skip(1).any(|i| page.key_at(i - 1) >= page.key_at(i)) {
+            return Err(Error::Corruption("records are not in key order".to_string()));
+        }
+        if !records.clone().all(|i| page.covers(page.key_at(i))) {
+            return Err(Error::Corruption("a record lies outside the page's fences".to_string()));
+        }
+
+        Ok(Self { page })
     }
 
     /// Binary search delegated to internal Page.
@@ -73,70 +136,124 @@ impl LeafPage {
         self.page.binary_search(key)
     }
 
+    /// Inserts or replaces the record for key (see Page::insert).
     pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
-        self.page.insert(key, value)
+        self.page.insert(key, value, RecordType::Insert)
+    }
+
+    /// Removes the record for key, if present.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        self.page.remove(key)
     }
 
+    /// True if insert would succeed.
     pub fn can_fit(&self, key: &[u8], value: &[u8]) -> bool {
-        let kv_meta_size = 8;
-        let total_size = self.page.kv_metas.len() * kv_meta_size
-            + self.page.data.len()
-            + key.len()
-            + value.len()
-            + 12; // NodeMeta size
-
-        total_size <= LEAF_PAGE_SIZE
+        self.page.can_insert(key, value, RecordType::Insert)
+    }
+
+    /// True if the live records (fences included) take up less than
+    /// 1/LEAF_UNDERFLOW_DIVISOR of the page, so the leaf should be merged with a neighbour.
+    pub fn is_underfull(&self) -> bool {
+        let page = &self.page;
+        let live = 12 + page.kv_metas.len() * 8 + page.data.len() - page.dead_bytes();
+        live < page.node_meta.node_size as usize / LEAF_UNDERFLOW_DIVISOR
     }
 
-    pub fn flush_to_disk(&self, offset: u64) {
-        let mut file = OpenOptions::new()
-            .write(true)
-            .open("storage.bftree") // example file
-            .expect("Failed to open file");
+    /// Writes the LeafPage to the page file at the given offset.
+    pub fn flush_to_disk(&self, storage: &PageFile, offset: u64) -> Result<()> {
+        storage.write_page(offset, &self.to_bytes()?)?;
+        Ok(())
+    }
 
-        file.seek(SeekFrom::Start(offset)).unwrap();
+    /// CRC-32C of a serialized page, with the checksum bytes taken as zero.
+    fn checksum(buffer: &[u8]) -> u32 {
+        let crc = crc32c_extend(0, &buffer[..CHECKSUM_START]);
+        let crc = crc32c_extend(crc, &[0; CHECKSUM_END - CHECKSUM_START]);
+        crc32c_extend(crc, &buffer[CHECKSUM_END..])
+    }
 
-        let meta_bytes = self.page.node_meta.serialize().unwrap();
-        file.write_all(&meta_bytes).unwrap();
+    /// Serializes the LeafPage into a node_size buffer:
+    /// NodeMeta, then the KVMeta array, then the data block, zero padded.
+    /// The page's CRC-32C is stored in the NodeMeta leaf field, flagged in its flags byte.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let page_size = self.page.node_meta.node_size as usize;
+        let mut buffer = Vec::with_capacity(page_size);
 
+        buffer.extend_from_slice(&self.page.node_meta.serialize()?);
         for kv in &self.page.kv_metas {
-            let kv_bytes = kv.serialize().unwrap();
-            file.write_all(&kv_bytes).unwrap();
+            buffer.extend_from_slice(&kv.serialize()?);
         }
+        buffer.extend_from_slice(&self.page.data);
 
-        file.write_all(&self.page.data).unwrap();
+        buffer.resize(page_size, 0);
+        buffer[2] |= CHECKSUM_FLAG;
+        buffer[CHECKSUM_START..12].fill(0);
+        let checksum = Self::checksum(&buffer);
+        buffer[CHECKSUM_START..CHECKSUM_END].copy_from_slice(&checksum.to_le_bytes());
+        Ok(buffer)
     }
 
-    pub fn split(&mut self) -> (LeafPage, LeafPage, Vec<u8>) {
-        let mid = self.page.kv_metas.len() / 2;
-        let split_key = {
-            let kv = &self.page.kv_metas[mid];
-            let start = kv.offset as usize;
-            let end = start + kv.key_size as usize;
-            self.page.data[start..end].to_vec()
-        };
-
-        let mut left = Page::new(self.page.node_meta.clone());
-        let mut right = Page::new(self.page.node_meta.clone());
+    /// Returns all records (without the fences) as owned (key, value) pairs in key order.
+    pub fn records(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.page.record_range()
+            .map(|i| (self.page.key_at(i).to_vec(), self.page.value_at(i).to_vec()))
+            .collect()
+    }
 
-        for (i, kv) in self.page.kv_metas.iter().enumerate() {
-            let start = kv.offset as usize;
-            let end = start + kv.key_size as usize + kv.value_size as usize;
-            let key = &self.page.data[start..start + kv.key_size as usize];
-            let val = &self.page.data[start + kv.key_size as usize..end];
+    /// Splits sorted records that overflow one page into evenly filled leaf pages.
+    /// Returns (low key, page) pairs in key order; each low key after the first
+    /// is the separator for the parent InnerNode.
+    ///
+    /// The pages split the fences of the page the records came from: the first
+    /// keeps its low fence, the last its high fence, and neighbours meet at the separator.
+    pub fn split(records: Vec<(Vec<u8>, Vec<u8>)>, fences: &Fences, page_size: usize) -> Vec<(Vec<u8>, LeafPage)> {
+        let record_size = |(k, v): &(Vec<u8>, Vec<u8>)| 8 + k.len() + v.len(); // KVMeta + data
+        // Fence keys are record keys or the outer fences, so reserve room for the longest
+        let longest_fence = records
+            .iter()
+            .map(|(k, _)| k.len())
+            .chain([fences.low.len(), fences.high.as_ref().map_or(0, |h| h.len())])
+            .max()
+            .unwrap_or(0);
+        let usable = page_size - 12 - 2 * (8 + longest_fence); // minus NodeMeta and fences
+        let total: usize = records.iter().map(record_size).sum();
+        let target = total / total.div_ceil(usable).max(1);
 
-            if i < mid {
-                left.insert(key, val);
-            } else {
-                right.insert(key, val);
+        let mut groups: Vec<Vec<(Vec<u8>, Vec<u8>)>> = Vec::new();
+        let mut filled = 0;
+        for record in records {
+            let size = record_size(&record);
+            if groups.is_empty() || filled + size > target {
+                groups.push(Vec::new());
+                filled = 0;
             }
+            groups.last_mut().unwrap().push(record);
+            filled += size;
+        }
+        if groups.is_empty() {
+            groups.push(Vec::new());
         }
 
-        (
-            LeafPage { page: left },
-            LeafPage { page: right },
-            split_key,
-        )
+        let mut low_keys: Vec<Vec<u8>> = groups.iter().map(|g| g.first().map_or(Vec::new(), |(k, _)| k.clone())).collect();
+        low_keys[0] = fences.low.clone();
+        let mut pages = Vec::with_capacity(groups.len());
+        for (i, group) in groups.into_iter().enumerate() {
+            let high = low_keys.get(i + 1).cloned().or_else(|| fences.high.clone());
+            let mut page = LeafPage::with_size(page_size);
+            page.page.set_fences(&Fences { low: low_keys[i].clone(), high });
+            for (k, v) in group {
+                page.insert(&k, &v);
+            }
+            pages.push((low_keys[i].clone(), page));
+        }
+        pages[0].0.clear();
+        pages
     }
 
 }
+
+impl Default for LeafPage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -3,7 +3,16 @@ pub mod bf_tree; pub use bf_tree::*;
 pub mod page; pub use page::*; 
 pub mod mini_page; pub use mini_page::*; 
 pub mod inner_node; pub use inner_node::*;
-// pub mod buffer_pool; pub use buffer_pool::*; // caches mini-pages (supports variable length pages)
+pub mod buffer_pool; pub use buffer_pool::*; // the memory region the mini-pages are allocated from (supports variable length pages)
 pub mod leaf_page; pub use leaf_page::*; // the on-disk leaf pages
 pub mod mapping_table; pub use mapping_table::*; // the mapping table for leaf and mini pages
-pub mod page_id_allocator; 
+pub mod range_scan; pub use range_scan::*; // ordered range scans over mini and leaf pages
+pub mod page_id_allocator; pub use page_id_allocator::*; // hands out logical page IDs
+pub mod storage; pub use storage::*; // the page file holding leaf pages
+pub mod file_header; pub use file_header::*; // the versioned, double-buffered superblock of the page file
+pub mod error; pub use error::*; // the Error and Result types returned by the tree
+pub mod wal; pub use wal::*; // the write-ahead log of buffered inserts and deletes
+pub mod checkpoint; pub use checkpoint::*; // snapshots of the inner nodes and mapping table
+pub mod epoch; pub use epoch::*; // epoch-based reclamation of memory lock-free readers may hold
+pub mod crc32c; pub use crc32c::*; // CRC-32C checksums of on-disk pages and WAL records
+pub mod background_merge; pub use background_merge::*; // the worker that merges dirty mini-pages ahead of writers
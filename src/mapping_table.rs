@@ -1,58 +1,418 @@
 // src/mapping_table.rs
 
-use std::rc::Rc;
-use std::cell::RefCell;
-use crate::mini_page::MiniPage;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
 
+use crate::buffer_pool::PoolMemory;
+use crate::epoch::Collector;
+use crate::error::{Error, Result};
 use crate::mini_page::MiniPage;
-use crate::page_id_allocator::PageIdAllocator;
+
+/// A mapping entry: (buffer pool address of the cached mini-page, leaf page disk offset).
+pub type MappingEntry = (Option<u64>, u64);
+
+const LATCH_BIT: u64 = 1; // set while a PageGuard holds the entry
+const MINI_PAGE_BIT: u64 = 2; // the word points at a cached mini-page, not at a leaf
+const FLAG_BITS: u64 = LATCH_BIT | MINI_PAGE_BIT;
+const UNMAPPED: u64 = 0; // offset 0 is the file header, never a leaf
+
+const FIRST_SEGMENT_LEN: usize = 1024;
+const SEGMENT_COUNT: usize = 32; // segment i holds FIRST_SEGMENT_LEN << i entries
+
+/// Number of page IDs the mapping table can hold.
+pub const MAX_PAGE_IDS: usize = FIRST_SEGMENT_LEN * ((1 << SEGMENT_COUNT) - 1);
+
+/// The state of one logical page, as seen through its PageGuard.
+#[derive(Clone)]
+pub struct PageEntry {
+    pub mini_page: Option<(u64, MiniPage)>, // cached mini-page and its buffer pool address
+    pub disk_offset: u64,                   // disk offset of the base leaf page
+}
+
+impl PageEntry {
+    /// The (mini-page address, disk offset) view of the entry.
+    pub fn mapping(&self) -> MappingEntry {
+        (self.mini_page.as_ref().map(|(addr, _)| *addr), self.disk_offset)
+    }
+
+    /// Packs the entry into an unlatched word, writing the mini-page into its
+    /// slot of memory. Panics if the disk offset would be read back as a
+    /// tagged pointer.
+    ///
+    /// # Safety
+    /// The caller must own the mini-page's slot (see PoolMemory::write).
+    unsafe fn into_word(self, memory: &PoolMemory) -> u64 {
+        match self.mini_page {
+            Some((addr, mut mini_page)) => {
+                let cached = CachedMiniPage::store(memory, addr, &mut mini_page, self.disk_offset);
+                CachedMiniPage::into_word(cached)
+            }
+            None => {
+                assert!(Self::valid_offset(self.disk_offset), "disk offset {} cannot be mapped", self.disk_offset);
+                self.disk_offset
+            }
+        }
+    }
+
+    /// True if a leaf at disk_offset can be packed into a word: the offset
+    /// leaves the flag bits clear and is not UNMAPPED.
+    fn valid_offset(disk_offset: u64) -> bool {
+        disk_offset != UNMAPPED && disk_offset & FLAG_BITS == 0
+    }
+
+    /// Unpacks a word written by into_word, reading the mini-page out of its slot.
+    /// Returns the word's box, or null if no mini-page was cached.
+    ///
+    /// # Safety
+    /// The caller must hold the word's latch.
+    unsafe fn read_word(word: u64, memory: &PoolMemory) -> (Self, *mut CachedMiniPage) {
+        let cached = CachedMiniPage::from_word(word);
+        if cached.is_null() {
+            return (Self { mini_page: None, disk_offset: word & !FLAG_BITS }, ptr::null_mut());
+        }
+        let mini_page = MiniPage::from_bytes(memory.read((*cached).addr, (*cached).size));
+        (Self { mini_page: Some(((*cached).addr, mini_page)), disk_offset: (*cached).leaf }, cached)
+    }
+}
+
+/// A cached mini-page, as referenced by a mapping word: the buffer pool slot
+/// holding it, and its leaf's disk offset.
+/// The fields never change once the box is published, so lock-free readers
+/// may read them (while pinned) even when the entry is latched; the slot's
+/// bytes belong to whoever holds the latch. The box is retired to the
+/// collector when the entry stops referencing it.
+#[derive(PartialEq, Eq)]
+struct CachedMiniPage {
+    addr: u64,
+    size: usize,
+    leaf: u64,
+}
+
+impl CachedMiniPage {
+    /// Writes mini_page into its slot at addr, with leaf as its leaf offset.
+    /// Panics if the mini-page outgrew its node_size.
+    ///
+    /// # Safety
+    /// The caller must own the slot at addr, which must be node_size bytes.
+    unsafe fn store(memory: &PoolMemory, addr: u64, mini_page: &mut MiniPage, leaf: u64) -> Self {
+        mini_page.page.node_meta.leaf = leaf;
+        let size = mini_page.page.node_meta.node_size as usize;
+        let bytes = mini_page.to_bytes();
+        assert!(bytes.len() <= size, "{} byte mini-page overflows its {} byte slot", bytes.len(), size);
+        memory.write(addr, &bytes);
+        Self { addr, size, leaf }
+    }
+
+    /// Boxes the cached mini-page into a tagged word.
+    fn into_word(self) -> u64 {
+        let cached = Box::into_raw(Box::new(self)) as u64;
+        debug_assert_eq!(cached & FLAG_BITS, 0);
+        cached | MINI_PAGE_BIT
+    }
+
+    /// The box a word points at, or null if it caches no mini-page.
+    fn from_word(word: u64) -> *mut Self {
+        if word & MINI_PAGE_BIT == 0 {
+            return ptr::null_mut();
+        }
+        (word & !FLAG_BITS) as *mut Self
+    }
+}
+
+/// Exclusive access to a page's entry; the page latch is held until the guard
+/// is dropped, which publishes the (possibly changed) entry.
+pub struct PageGuard<'a> {
+    table: &'a MappingTable,
+    word: &'a AtomicU64,
+    latched: u64,                 // the word as written when the latch was taken
+    cached: *mut CachedMiniPage,  // the box the mini-page was read through, or null
+    entry: Option<PageEntry>,     // Some until dropped
+}
+
+impl Deref for PageGuard<'_> {
+    type Target = PageEntry;
+
+    fn deref(&self) -> &PageEntry {
+        self.entry.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PageGuard<'_> {
+    fn deref_mut(&mut self) -> &mut PageEntry {
+        self.entry.as_mut().unwrap()
+    }
+}
+
+impl PageGuard<'_> {
+    /// Unmaps the page ID, e.g. once its leaf was merged into a neighbour,
+    /// and releases the latch. The mini-page must have been taken out.
+    pub fn unmap(mut self) {
+        let entry = self.entry.take().unwrap();
+        debug_assert!(entry.mini_page.is_none(), "unmapped a page with a cached mini-page");
+        let published = self.word.compare_exchange(self.latched, UNMAPPED, Ordering::AcqRel, Ordering::Acquire);
+        debug_assert!(published.is_ok(), "latched mapping entry changed under its guard");
+        if !self.cached.is_null() {
+            // SAFETY: the box is unlinked
+            self.table.collector.retire(unsafe { Box::from_raw(self.cached) });
+        }
+    }
+}
+
+impl Drop for PageGuard<'_> {
+    fn drop(&mut self) {
+        // None once unmapped
+        let PageEntry { mini_page, disk_offset } = match self.entry.take() {
+            Some(entry) => entry,
+            None => return,
+        };
+        // A mini-page install or leaf relocation becomes visible with this CAS;
+        // nothing else writes a latched word.
+        let mut unmoved = false;
+        let word = match mini_page {
+            Some((addr, mut mini_page)) => {
+                // SAFETY: the latch gives this guard the mini-page's slot; the box
+                // stays allocated while the latch is held
+                let cached = unsafe { CachedMiniPage::store(&self.table.memory, addr, &mut mini_page, disk_offset) };
+                unmoved = !self.cached.is_null() && unsafe { *self.cached == cached };
+                if unmoved { self.latched & !LATCH_BIT } else { cached.into_word() }
+            }
+            // SAFETY: there is no slot to write
+            None => unsafe { PageEntry { mini_page: None, disk_offset }.into_word(&self.table.memory) },
+        };
+        let published = self.word.compare_exchange(self.latched, word, Ordering::AcqRel, Ordering::Acquire);
+        debug_assert!(published.is_ok(), "latched mapping entry changed under its guard");
+
+        if !unmoved && !self.cached.is_null() {
+            // SAFETY: the box is unlinked
+            self.table.collector.retire(unsafe { Box::from_raw(self.cached) });
+        }
+    }
+}
 
 /// The MappingTable maps logical page IDs to:
-/// - an optional in-memory MiniPage (cached hot records)
+/// - an optional MiniPage (cached hot records), at its buffer pool address
 /// - the disk offset of the base leaf page (always exists)
+///
+/// Each entry is one AtomicU64: a disk offset, or a pointer to a box holding
+/// the cached mini-page's address and its leaf's disk offset, tagged with
+/// MINI_PAGE_BIT, plus LATCH_BIT. Latches are taken with compare-and-swap
+/// and the new entry is published with one when they are released.
+///
+/// The mini-pages live in the buffer pool's memory. A PageGuard reads its
+/// page's mini-page out of the slot when it takes the latch and writes it
+/// back, to the address the entry then holds, when it releases it.
+///
+/// Entries live in segments of doubling size that are allocated on demand and
+/// never move, so the table grows without blocking or invalidating readers.
+///
+/// get reads an entry without latching it; mini-page boxes it may be reading
+/// are retired to the collector rather than freed.
 pub struct MappingTable {
-    table: Vec<Option<(Option<Rc<RefCell<MiniPage>, u64)>>, // Vec acts as indirection array
+    segments: [AtomicPtr<AtomicU64>; SEGMENT_COUNT],
+    collector: Collector,      // pinned by get, frees unlinked mini-page boxes
+    memory: Arc<PoolMemory>,   // the buffer pool region the mini-pages are stored in
 }
 
 impl MappingTable {
-    /// Create a new MappingTable with an initial capacity for page IDs.
-    pub fn new(initial_capacity: usize) -> Self {
-        Self {
-            table: vec![None; initial_capacity],
+    /// Create a new MappingTable with an initial capacity for page IDs, over
+    /// the buffer pool memory its mini-pages are allocated from.
+    /// Fails with Error::InvalidState if the capacity exceeds MAX_PAGE_IDS.
+    pub fn new(initial_capacity: usize, memory: Arc<PoolMemory>) -> Result<Self> {
+        let table = Self {
+            segments: std::array::from_fn(|_| AtomicPtr::new(ptr::null_mut())),
+            collector: Collector::new(),
+            memory,
+        };
+        if initial_capacity > 0 {
+            table.word_or_grow(initial_capacity - 1)?;
+        }
+        Ok(table)
+    }
+
+    /// Segment index and position within it for a page ID.
+    fn locate(page_id: usize) -> (usize, usize) {
+        let slot = page_id / FIRST_SEGMENT_LEN + 1;
+        let segment = (usize::BITS - 1 - slot.leading_zeros()) as usize;
+        let segment_start = FIRST_SEGMENT_LEN * ((1 << segment) - 1);
+        (segment, page_id - segment_start)
+    }
+
+    fn segment_len(segment: usize) -> usize {
+        FIRST_SEGMENT_LEN << segment
+    }
+
+    /// The word for page_id, if its segment exists.
+    fn word(&self, page_id: usize) -> Option<&AtomicU64> {
+        let (segment, idx) = Self::locate(page_id);
+        let base = self.segments.get(segment)?.load(Ordering::Acquire);
+        if base.is_null() {
+            return None;
+        }
+        // SAFETY: segments are allocated with segment_len entries and freed only on drop
+        Some(unsafe { &*base.add(idx) })
+    }
+
+    /// The word for page_id, allocating its segment (and any before it) if needed.
+    fn word_or_grow(&self, page_id: usize) -> Result<&AtomicU64> {
+        if page_id >= MAX_PAGE_IDS {
+            return Err(Error::InvalidState(format!("page ID {} exceeds the mapping table", page_id)));
+        }
+        let (last, _) = Self::locate(page_id);
+        for segment in 0..=last {
+            if !self.segments[segment].load(Ordering::Acquire).is_null() {
+                continue;
+            }
+            let words: Box<[AtomicU64]> = (0..Self::segment_len(segment)).map(|_| AtomicU64::new(UNMAPPED)).collect();
+            let new = Box::into_raw(words) as *mut AtomicU64;
+            if self.segments[segment]
+                .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                // Another thread installed the segment first
+                // SAFETY: new was never published
+                drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(new, Self::segment_len(segment))) });
+            }
+        }
+        Ok(self.word(page_id).unwrap())
+    }
+
+    /// Insert or replace the entry for a logical page ID.
+    /// The entry must not be latched, and a mini-page must have been allocated
+    /// its slot for page_id.
+    /// Fails with Error::InvalidState if the page ID exceeds MAX_PAGE_IDS, or
+    /// if disk_offset is zero or not aligned to a page.
+    pub fn insert(&self, page_id: usize, mini_page: Option<(u64, MiniPage)>, disk_offset: u64) -> Result<()> {
+        if !PageEntry::valid_offset(disk_offset) {
+            return Err(Error::InvalidState(format!("page ID {} mapped to unaligned disk offset {}", page_id, disk_offset)));
+        }
+        let slot = self.word_or_grow(page_id)?;
+        // SAFETY: the caller allocated the slot for this page
+        let word = unsafe { PageEntry { mini_page, disk_offset }.into_word(&self.memory) };
+        let old = slot.swap(word, Ordering::AcqRel);
+        debug_assert_eq!(old & LATCH_BIT, 0, "replaced a latched mapping entry");
+        let cached = CachedMiniPage::from_word(old);
+        if !cached.is_null() {
+            // SAFETY: the old word is no longer reachable through the table
+            self.collector.retire(unsafe { Box::from_raw(cached) });
+        }
+        Ok(())
+    }
+
+    /// Latches the entry of the given page ID, waiting while another thread holds it.
+    /// Returns None if the page ID is not mapped.
+    pub fn lock(&self, page_id: usize) -> Option<PageGuard<'_>> {
+        let word = self.word(page_id)?;
+        loop {
+            let current = word.load(Ordering::Acquire);
+            if current == UNMAPPED {
+                return None;
+            }
+            if current & LATCH_BIT == 0 {
+                if let Some(guard) = self.latch(word, current) {
+                    return Some(guard);
+                }
+                continue;
+            }
+            std::thread::yield_now();
         }
     }
 
-    /// Insert or update the mapping for a logical page ID.
-    pub fn insert(&mut self, page_id: usize, mini_page_rc: Option<Rc<RefCell<MiniPage>>>, disk_offset: u64) {
-        if page_id >= self.table.len() {
-            self.table.resize(page_id + 1, None);
+    /// Latches the entry of the given page ID unless another thread holds it.
+    /// Returns None if the page ID is not mapped or is latched.
+    pub fn try_lock(&self, page_id: usize) -> Option<PageGuard<'_>> {
+        let word = self.word(page_id)?;
+        loop {
+            let current = word.load(Ordering::Acquire);
+            if current == UNMAPPED || current & LATCH_BIT != 0 {
+                return None;
+            }
+            if let Some(guard) = self.latch(word, current) {
+                return Some(guard);
+            }
         }
-        self.table[page_id] = Some((mini_page_rc, disk_offset));
     }
 
-    /// Update just the MiniPage for a given logical page ID.
-    pub fn update_mini_page(&mut self, page_id: usize, mini_page_rc: Rc<RefCell<MiniPage>>) {
-        if let Some((_, disk_offset)) = self.get(page_id) {
-            self.table[page_id] = Some((Some(mini_page_rc), disk_offset));
-        } else {
-            panic!("Cannot update mini-page: page_id not found in mapping table");
+    /// Sets the latch bit of word if it still holds current (unlatched).
+    fn latch<'a>(&'a self, word: &'a AtomicU64, current: u64) -> Option<PageGuard<'a>> {
+        let latched = current | LATCH_BIT;
+        word.compare_exchange_weak(current, latched, Ordering::AcqRel, Ordering::Acquire).ok()?;
+        // SAFETY: the latch gives this guard ownership of the mini-page's slot
+        let (entry, cached) = unsafe { PageEntry::read_word(current, &self.memory) };
+        Some(PageGuard { table: self, word, latched, cached, entry: Some(entry) })
+    }
+
+    /// Get (mini-page address, disk_offset) for the given page ID.
+    /// Does not wait for the page latch: a latched entry reads as it was
+    /// before the latch was taken.
+    pub fn get(&self, page_id: usize) -> Option<MappingEntry> {
+        let word = self.word(page_id)?;
+        let _guard = self.collector.pin();
+        let current = word.load(Ordering::Acquire);
+        if current == UNMAPPED {
+            return None;
         }
+        if current & MINI_PAGE_BIT == 0 {
+            return Some((None, current & !FLAG_BITS));
+        }
+        // SAFETY: boxes are retired, not freed, while the collector is pinned
+        let cached = (current & !FLAG_BITS) as *const CachedMiniPage;
+        Some(unsafe { (Some((*cached).addr), (*cached).leaf) })
     }
 
-    /// Get (mini_page, disk_offset) for the given page ID.
-    pub fn get(&self, page_id: usize) -> Option<(Option<Rc<RefCell<MiniPage>>>, u64)> {
-        self.table.get(page_id).and_then(|entry| entry.clone())
+    /// A copy of the mini-page cached for the given page ID.
+    pub fn mini_page(&self, page_id: usize) -> Option<MiniPage> {
+        let entry = self.lock(page_id)?;
+        entry.mini_page.as_ref().map(|(_, mini_page)| mini_page.clone())
     }
 
     /// Check if the mapping table contains an entry for the page ID.
     pub fn contains(&self, page_id: usize) -> bool {
-        page_id < self.table.len() && self.table[page_id].is_some()
+        self.word(page_id).is_some_and(|word| word.load(Ordering::Acquire) != UNMAPPED)
     }
 
-    pub fn clear_mini_page(&mut self, page_id: usize) {
-        if let Some((_, disk_offset)) = self.get(page_id) {
-            self.table[page_id] = Some((None, disk_offset));
+    /// Frees the unlinked mini-page boxes no reader can reach any more.
+    /// Returns the number of boxes freed.
+    pub fn collect(&self) -> usize {
+        self.collector.collect()
+    }
+
+    /// Snapshot of (page_id, entry) for every mapped page ID, in page ID order.
+    pub fn entries(&self) -> impl Iterator<Item = (usize, MappingEntry)> {
+        let mut entries = Vec::new();
+        for segment in 0..SEGMENT_COUNT {
+            if self.segments[segment].load(Ordering::Acquire).is_null() {
+                break;
+            }
+            let segment_start = FIRST_SEGMENT_LEN * ((1 << segment) - 1);
+            for page_id in segment_start..segment_start + Self::segment_len(segment) {
+                if let Some(mapping) = self.get(page_id) {
+                    entries.push((page_id, mapping));
+                }
+            }
         }
+        entries.into_iter()
     }
+}
 
+impl Drop for MappingTable {
+    fn drop(&mut self) {
+        for segment in 0..SEGMENT_COUNT {
+            let base = *self.segments[segment].get_mut();
+            if base.is_null() {
+                continue;
+            }
+            let len = Self::segment_len(segment);
+            // SAFETY: the segment was allocated as a boxed slice of len words, and
+            // no guard can outlive the table
+            let words = unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(base, len)) };
+            for word in words.iter() {
+                let cached = CachedMiniPage::from_word(word.load(Ordering::Relaxed));
+                if !cached.is_null() {
+                    drop(unsafe { Box::from_raw(cached) });
+                }
+            }
+        }
+    }
 }
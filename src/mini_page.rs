@@ -1,8 +1,10 @@
 // src/mini_page.rs
 
-use crate::page::{Page, NodeMeta, PageType, RecordType};
-use crate::config::{MINI_PAGE_MIN_SIZE, MINI_PAGE_MAX_SIZE};
+use crate::page::{Fences, KVMeta, Page, NodeMeta, PageType, RecordType};
+use crate::config::{BfTreeOptions, MINI_PAGE_MIN_SIZE};
+use crate::error::Result;
 use crate::leaf_page::LeafPage;
+use crate::storage::PageFile;
 
 #[derive(Clone)]
 pub struct MiniPage {
@@ -10,10 +12,15 @@ pub struct MiniPage {
 }
 
 impl MiniPage {
-    /// Creates a new MiniPage for a given leaf disk offset.
+    /// Creates a new MiniPage of the default minimum size for a given leaf disk offset.
     pub fn new(leaf_offset: u64) -> Self {
+        Self::with_size(leaf_offset, MINI_PAGE_MIN_SIZE)
+    }
+
+    /// Creates a new MiniPage of the given size for a given leaf disk offset.
+    pub fn with_size(leaf_offset: u64, size: usize) -> Self {
         let node_meta = NodeMeta::new(
-            MINI_PAGE_MIN_SIZE as u16, // Minimal initial size (can grow dynamically)
+            size as u16, // initial size (can grow dynamically)
             PageType::MiniPage,
             false, // split flag initially false
             0,     // record count
@@ -24,22 +31,128 @@ impl MiniPage {
         Self { page }
     }
 
+    /// Creates a mini-page in the smallest size class that holds its leaf's
+    /// fences and a first record.
+    /// Returns None if they do not fit even the largest mini-page.
+    pub fn with_record(
+        leaf_offset: u64,
+        fences: &Fences,
+        key: &[u8],
+        value: &[u8],
+        record_type: RecordType,
+        options: &BfTreeOptions,
+    ) -> Option<Self> {
+        let mut mini_page = Self::with_size(leaf_offset, options.mini_page_min_size);
+        while !(mini_page.page.set_fences(fences) && mini_page.insert(key, value, record_type)) {
+            let new_size = mini_page.next_size(options.mini_page_max_size);
+            if new_size == 0 {
+                return None;
+            }
+            mini_page.resize(new_size as usize);
+        }
+        Some(mini_page)
+    }
+
+    /// The longest key a mini-page of max_size accepts.
+    /// Fence keys are keys too, so a page always has room for two of them.
+    pub fn max_key_size(max_size: usize) -> usize {
+        max_size / 8
+    }
+
+    /// True if a record of this size can be buffered in a mini-page at all,
+    /// next to fence keys of the longest key size, and its value fits the
+    /// KVMeta value_size field.
+    pub fn fits_record(key: &[u8], value: &[u8], max_size: usize) -> bool {
+        let max_key_size = Self::max_key_size(max_size);
+        key.len() <= max_key_size
+            && value.len() <= KVMeta::MAX_SIZE
+            && 12 + 3 * 8 + 2 * max_key_size + key.len() + value.len() <= max_size
+    }
+
+    /// Serializes the mini-page for its buffer pool slot: NodeMeta, then the
+    /// KVMeta array, then the data block. At most node_size bytes; the rest of
+    /// the slot is left as is.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.page.node_meta.node_size as usize);
+        // Neither serializer can fail writing to its fixed-size array
+        buffer.extend_from_slice(&self.page.node_meta.serialize().unwrap());
+        for kv in &self.page.kv_metas {
+            buffer.extend_from_slice(&kv.serialize().unwrap());
+        }
+        buffer.extend_from_slice(&self.page.data);
+        buffer
+    }
+
+    /// Parses a mini-page from the slot bytes written by to_bytes.
+    /// The slot is trusted: it is only written from a MiniPage. Data past the
+    /// end of the furthest record is dead space and is not read back.
+    pub fn from_bytes(buffer: &[u8]) -> Self {
+        let node_meta = NodeMeta::deserialize(buffer[0..12].try_into().unwrap()).unwrap();
+        let data_start = 12 + node_meta.record_count as usize * 8;
+        let kv_metas: Vec<KVMeta> = buffer[12..data_start]
+            .chunks_exact(8)
+            .map(|kv_bytes| KVMeta::deserialize(kv_bytes.try_into().unwrap()).unwrap())
+            .collect();
+        let data_len = kv_metas
+            .iter()
+            .map(|kv| kv.offset as usize + kv.key_size as usize + kv.value_size as usize)
+            .max()
+            .unwrap_or(0);
+        let data = buffer[data_start..data_start + data_len].to_vec();
+        Self { page: Page { node_meta, kv_metas, data } }
+    }
+
     /// Binary search delegated to internal Page.
     pub fn binary_search(&self, key: &[u8]) -> Option<Vec<u8>> {
         self.page.binary_search(key)
     }
 
-    pub fn insert(&mut self, key: &[u8], value: &[u8], record_type: RecordType) -> bool {
-        if self.page.insert(key, value, record_type as u8) {
-            return true; 
+    /// Looks up key and returns the record type alongside the value.
+    pub fn lookup(&self, key: &[u8]) -> Option<(RecordType, Vec<u8>)> {
+        self.page.lookup(key)
+    }
+
+    /// Looks up key like `lookup`, and on a hit sets the record's reference bit
+    /// so it gets a second chance at the next merge.
+    pub fn reference(&mut self, key: &[u8]) -> Option<(RecordType, Vec<u8>)> {
+        let idx = self.page.find_index(key)?;
+        self.page.kv_metas[idx].ref_flag = 1;
+        self.page.lookup(key)
+    }
+
+    /// True if any record was referenced since the last merge.
+    pub fn is_referenced(&self) -> bool {
+        self.page.kv_metas.iter().any(|kv| kv.ref_flag != 0)
+    }
+
+    /// True if the mini-page holds records (Insert or Tombstone) not merged into the leaf yet.
+    pub fn is_dirty(&self) -> bool {
+        self.page.kv_metas[self.page.record_range()].iter().any(|kv| kv.record_type().is_dirty())
+    }
+
+    /// Clears every reference bit, so the next merge keeps no record cached.
+    pub fn clear_references(&mut self) {
+        for kv in &mut self.page.kv_metas {
+            kv.ref_flag = 0;
         }
     }
 
-    pub fn next_size(&self) -> u16 {
+    /// Buffers a record, replacing any older record for the same key (see Page::insert).
+    pub fn insert(&mut self, key: &[u8], value: &[u8], record_type: RecordType) -> bool {
+        self.page.insert(key, value, record_type)
+    }
+
+    /// True if insert would succeed without growing the mini-page.
+    pub fn can_insert(&self, key: &[u8], value: &[u8], record_type: RecordType) -> bool {
+        self.page.can_insert(key, value, record_type)
+    }
+
+    /// The next size class up to max_size, or 0 if the mini-page cannot grow.
+    pub fn next_size(&self, max_size: usize) -> u16 {
         let current = self.page.node_meta.node_size;
         let next = current.saturating_mul(2);
-        if next <= MINI_PAGE_MAX_SIZE {
-            next as u16
+        if next as usize <= max_size {
+            next
         } else {
             0 // cannot grow further
         }
@@ -72,14 +185,69 @@ impl MiniPage {
         };
     }
 
-    pub fn merge(&mut self) {
+    /// Compacts the mini-page and shrinks it to the smallest size class (from min_size up) that holds its records.
+    pub fn shrink_to_fit(&mut self, min_size: usize) {
+        self.page.compact();
+        let needed = 12 + self.page.kv_metas.len() * 8 + self.page.data.len();
+        let mut size = min_size;
+        while size < needed {
+            size *= 2;
+        }
+        if size < self.page.node_meta.node_size as usize {
+            self.resize(size);
+        }
+    }
+
+    /// Moves a clean mini-page to new fences after its leaf was merged with or
+    /// borrowed from a neighbour: records outside them are dropped, and so
+    /// is everything if the fence keys do not fit.
+    pub fn set_fences(&mut self, fences: &Fences) {
+        let outside: Vec<Vec<u8>> = self.page.record_range()
+            .map(|i| self.page.key_at(i))
+            .filter(|key| !fences.contains(key))
+            .map(|key| key.to_vec())
+            .collect();
+        for key in outside {
+            self.page.remove(&key);
+        }
+        if !self.page.set_fences(fences) {
+            self.page.kv_metas.clear();
+            self.page.data.clear();
+            self.page.node_meta.record_count = 0;
+        }
+    }
+
+    /// Merges dirty records into the leaf page.
+    /// Records referenced since the last merge stay cached (as clean Cache or
+    /// Phantom records, with the reference bit cleared); everything else is dropped.
+    ///
+    /// If the leaf overflows it is split. Every resulting page, including the one
+    /// with the lowest keys, is written to a freshly allocated page, so the leaf
+    /// the last checkpoint points at stays intact; node_meta.leaf is updated to
+    /// the new offset of the lowest keys.
+    /// A leaf that does not split is overwritten in place, or with copy_on_write
+    /// moved to a freshly allocated page the same way, so a torn write cannot
+    /// damage it.
+    /// Returns (separator key, disk offset) for every new sibling; the caller must
+    /// register them and the moved leaf in the mapping table and the parent InnerNode.
+    ///
+    /// On error the mini-page is left unchanged, so its records can be merged again.
+    pub fn merge(&mut self, storage: &PageFile, copy_on_write: bool) -> Result<Vec<(Vec<u8>, u64)>> {
         let leaf_offset = self.page.node_meta.leaf;
-        let mut leaf_page = LeafPage::load_from_disk(leaf_offset);
+        let mut leaf_page = LeafPage::load_from_disk(storage, leaf_offset)?;
+
+        // A leaf written without fences takes the mini-page's (set from routing);
+        // otherwise the leaf's fences are authoritative
+        if leaf_page.page.fence_count() == 0 {
+            leaf_page.page.set_fences(&self.page.fences());
+        }
+        let mut fences = leaf_page.page.fences();
 
         let mut dirty_records = Vec::new();
+        let mut deleted_keys = Vec::new();
         let mut hot_records = Vec::new();
 
-        for kv in &self.page.kv_metas {
+        for kv in &self.page.kv_metas[self.page.fence_count()..] {
             let key_start = kv.offset as usize;
             let key_end = key_start + kv.key_size as usize;
             let val_end = key_end + kv.value_size as usize;
@@ -87,54 +255,84 @@ impl MiniPage {
             let key = &self.page.data[key_start..key_end];
             let value = &self.page.data[key_end..val_end];
 
-            if kv.ref_flag != 0 {
-                // Hot record → retain in mini-page (copy into new buffer if needed later)
-                hot_records.push((key.to_vec(), value.to_vec(), kv.clone()));
-            } else if kv.type_flag == 0 {
+            let record_type = kv.record_type();
+            match record_type {
                 // Dirty insert → merge into leaf
-                dirty_records.push((key.to_vec(), value.to_vec()));
-            } else {
-                // Cold phantom/read cache → drop without writing to disk
+                RecordType::Insert => dirty_records.push((key.to_vec(), value.to_vec())),
+                // Dirty delete → remove from leaf
+                RecordType::Tombstone => deleted_keys.push(key.to_vec()),
+                // Phantom/read cache → already consistent with the leaf
+                RecordType::Cache | RecordType::Phantom => {}
+            }
+
+            if kv.ref_flag != 0 {
+                // Hot record → second chance: stays cached, now clean
+                let clean_type = match record_type {
+                    RecordType::Insert | RecordType::Cache => RecordType::Cache,
+                    RecordType::Tombstone | RecordType::Phantom => RecordType::Phantom,
+                };
+                hot_records.push((key.to_vec(), value.to_vec(), clean_type));
             }
         }
 
-        let needs_split = dirty_records.iter().any(|(k, v)| !leaf_page.can_fit(k, v));
+        // Tombstones and overwritten keys leave the leaf before new values go in
+        for k in &deleted_keys {
+            leaf_page.remove(k);
+        }
+        for (k, _) in &dirty_records {
+            leaf_page.remove(k);
+        }
+
+        // Reclaim the bytes of removed records before deciding to split
+        leaf_page.page.compact();
+        let needed = 12
+            + (leaf_page.page.kv_metas.len() + dirty_records.len()) * 8
+            + leaf_page.page.data.len()
+            + dirty_records.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+        let needs_split = needed > storage.page_size();
+        let mut new_siblings = Vec::new();
 
         if needs_split {
-            // Split the leaf and insert accordingly
-            let (mut left, mut right, split_key) = leaf_page.split();
+            // Combine leaf and dirty records (keys disjoint) and split
+            let mut records = leaf_page.records();
+            records.extend(dirty_records);
+            records.sort_by(|a, b| a.0.cmp(&b.0));
 
-            for (k, v) in dirty_records {
-                if k < split_key {
-                    let _ = left.insert(&k, &v);
-                } else {
-                    let _ = right.insert(&k, &v);
-                }
+            let mut pages = LeafPage::split(records, &fences, storage.page_size()).into_iter();
+            let (_, left) = pages.next().unwrap();
+            fences = left.page.fences();
+            let left_offset = storage.allocate_page();
+            left.flush_to_disk(storage, left_offset)?;
+
+            for (separator, right) in pages {
+                let right_offset = storage.allocate_page();
+                right.flush_to_disk(storage, right_offset)?;
+                new_siblings.push((separator, right_offset));
             }
+            self.page.node_meta.leaf = left_offset;
 
-            left.flush_to_disk();
-            right.flush_to_disk();
+            // Hot records for keys that moved to a sibling no longer belong here
+            hot_records.retain(|(k, _, _)| fences.contains(k));
         } else {
             for (k, v) in dirty_records {
                 let _ = leaf_page.insert(&k, &v);
             }
-            leaf_page.flush_to_disk();
+            let new_offset = if copy_on_write { storage.allocate_page() } else { leaf_offset };
+            leaf_page.flush_to_disk(storage, new_offset)?;
+            self.page.node_meta.leaf = new_offset;
         }
 
-        // Replace mini-page content with only hot records (optional optimization)
+        // Replace mini-page content with only hot records (reference bits cleared)
         self.page.kv_metas.clear();
         self.page.data.clear();
         self.page.node_meta.record_count = 0;
+        self.page.set_fences(&fences);
 
-        for (key, value, mut kv) in hot_records {
-            let offset = self.page.data.len() as u16;
-            self.page.data.extend_from_slice(&key);
-            self.page.data.extend_from_slice(&value);
-            kv.offset = offset;
-            kv.ref_flag = 0; // clear reference bit for future tracking
-            self.page.kv_metas.push(kv);
-            self.page.node_meta.record_count += 1;
+        for (key, value, record_type) in hot_records {
+            self.page.insert(&key, &value, record_type);
         }
+
+        Ok(new_siblings)
     }
 
 
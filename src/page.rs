@@ -2,7 +2,10 @@
 
 use std::cmp::Ordering;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Cursor, Result, Read, Write};
+use std::io::{Cursor, Read, Write};
+use std::ops::Range;
+
+use crate::error::{Error, Result};
 
 /// Distinguishes between mini-pages and leaf pages.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -89,21 +92,30 @@ pub enum RecordType {
     Phantom = 3,
 }
 
-impl From<u8> for RecordType {
-    fn from(value: u8) -> Self {
+impl TryFrom<u8> for RecordType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
         match value {
-            0 => RecordType::Insert,
-            1 => RecordType::Cache,
-            2 => RecordType::Tombstone,
-            3 => RecordType::Phantom,
-            _ => panic!("Invalid type_flag"),
+            0 => Ok(RecordType::Insert),
+            1 => Ok(RecordType::Cache),
+            2 => Ok(RecordType::Tombstone),
+            3 => Ok(RecordType::Phantom),
+            _ => Err(Error::Corruption(format!("invalid record type flag {}", value))),
         }
     }
 }
 
-impl Into<u8> for RecordType {
-    fn into(self) -> u8 {
-        self as u8
+impl RecordType {
+    /// Insert and Tombstone records are dirty: the leaf page does not reflect them yet.
+    pub fn is_dirty(self) -> bool {
+        matches!(self, RecordType::Insert | RecordType::Tombstone)
+    }
+}
+
+impl From<RecordType> for u8 {
+    fn from(record_type: RecordType) -> u8 {
+        record_type as u8
     }
 }
 
@@ -116,20 +128,49 @@ pub struct KVMeta {
     pub offset: u16,      // 16 bits
     pub type_flag: u8,    // 2 bits
     pub is_fence: bool,   // 1 bit
-    pub ref_flag: u8,     // 2 bits
+    pub ref_flag: u8,     // 1 bit
     pub lookahead: u16,   // 16 bits
 }
 
 impl KVMeta {
-    pub fn new(key_size: u16, value_size: u16, offset: u16, type_flag: u8, is_fence: bool, ref_flag: u8, lookahead: u16) -> Self {
-        Self {
-            key_size: key_size & 0x3FFF,
-            value_size: value_size & 0x3FFF,
+    /// The largest key_size and value_size the 14-bit fields hold.
+    pub const MAX_SIZE: usize = 0x3FFF;
+
+    /// Fails with Error::InvalidState if key_size or value_size exceeds MAX_SIZE.
+    pub fn new(key_size: usize, value_size: usize, offset: u16, type_flag: u8, is_fence: bool, ref_flag: u8, lookahead: u16) -> Result<Self> {
+        if key_size > Self::MAX_SIZE || value_size > Self::MAX_SIZE {
+            return Err(Error::InvalidState(format!(
+                "record of key size {} and value size {} exceeds the KVMeta limit of {}",
+                key_size, value_size, Self::MAX_SIZE
+            )));
+        }
+        Ok(Self {
+            key_size: key_size as u16,
+            value_size: value_size as u16,
             offset,
             type_flag: type_flag & 0x03,
             is_fence,
             ref_flag: ref_flag & 0x03,
             lookahead,
+        })
+    }
+
+    /// The lookahead of key: its first two bytes, big-endian, zero padded.
+    /// Keys with different lookaheads compare like their lookaheads, so only
+    /// equal lookaheads need the full key.
+    pub fn lookahead_of(key: &[u8]) -> u16 {
+        let first = key.first().copied().unwrap_or(0);
+        let second = key.get(1).copied().unwrap_or(0);
+        u16::from_be_bytes([first, second])
+    }
+
+    /// The record type encoded in the 2-bit type_flag.
+    pub fn record_type(&self) -> RecordType {
+        match self.type_flag & 0x03 {
+            0 => RecordType::Insert,
+            1 => RecordType::Cache,
+            2 => RecordType::Tombstone,
+            _ => RecordType::Phantom,
         }
     }
 
@@ -137,13 +178,13 @@ impl KVMeta {
     pub fn serialize(&self) -> Result<[u8; 8]> {
         let mut packed: u64 = 0;
 
-        packed |= (self.key_size as u64 & 0x3FFF) << 0;
+        packed |= self.key_size as u64 & 0x3FFF;
         packed |= (self.value_size as u64 & 0x3FFF) << 14;
         packed |= (self.offset as u64 & 0xFFFF) << 28;
         packed |= (self.type_flag as u64 & 0x03) << 44;
         packed |= (self.is_fence as u64 & 0x01) << 46;
-        packed |= (self.ref_flag as u64 & 0x03) << 47;
-        packed |= (self.lookahead as u64) << 49;
+        packed |= (self.ref_flag as u64 & 0x01) << 47;
+        packed |= (self.lookahead as u64) << 48;
 
         let mut buf = [0u8; 8];
         buf.copy_from_slice(&packed.to_le_bytes()[..8]);
@@ -154,13 +195,13 @@ impl KVMeta {
     pub fn deserialize(buf: &[u8; 8]) -> Result<Self> {
         let packed = u64::from_le_bytes(*buf);
 
-        let key_size = ((packed >> 0) & 0x3FFF) as u16;
+        let key_size = (packed & 0x3FFF) as u16;
         let value_size = ((packed >> 14) & 0x3FFF) as u16;
         let offset = ((packed >> 28) & 0xFFFF) as u16;
         let type_flag = ((packed >> 44) & 0x03) as u8;
         let is_fence = ((packed >> 46) & 0x01) != 0;
-        let ref_flag = ((packed >> 47) & 0x03) as u8;
-        let lookahead = ((packed >> 49) & 0xFFFF) as u16;
+        let ref_flag = ((packed >> 47) & 0x01) as u8;
+        let lookahead = ((packed >> 48) & 0xFFFF) as u16;
 
         Ok(Self {
             key_size,
@@ -174,7 +215,35 @@ impl KVMeta {
     }
 }
 
+/// The key range of a page: the low fence is inclusive, the high fence exclusive.
+/// An empty low fence and a missing high fence are unbounded.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fences {
+    pub low: Vec<u8>,
+    pub high: Option<Vec<u8>>,
+}
+
+impl Fences {
+    pub fn new(low: &[u8], high: Option<&[u8]>) -> Self {
+        Self { low: low.to_vec(), high: high.map(|h| h.to_vec()) }
+    }
+
+    /// True if key lies between the fences.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key >= self.low.as_slice() && self.high.as_deref().is_none_or(|high| key < high)
+    }
+
+    /// True if neither fence bounds the range.
+    pub fn is_unbounded(&self) -> bool {
+        self.low.is_empty() && self.high.is_none()
+    }
+}
+
 /// Generic Page struct shared by mini-pages and leaf pages.
+///
+/// A bounded page starts with two fence records (is_fence set, no value): the
+/// low fence key, then the high fence key (empty if unbounded). The key-value
+/// records follow in key order. NodeMeta.record_count counts the fence records too.
 #[derive(Clone)]
 pub struct Page {
     pub node_meta: NodeMeta,
@@ -194,24 +263,104 @@ impl Page {
 
     /// Performs binary search for target_key.
     pub fn binary_search(&self, target_key: &[u8]) -> Option<Vec<u8>> {
-        let mut left = 0;
+        self.lookup(target_key).map(|(_, value)| value)
+    }
+
+    /// Performs binary search for target_key and returns the record type with the value.
+    pub fn lookup(&self, target_key: &[u8]) -> Option<(RecordType, Vec<u8>)> {
+        let idx = self.find_index(target_key)?;
+        let kv = &self.kv_metas[idx];
+        Some((kv.record_type(), self.value_at(idx).to_vec()))
+    }
+
+    /// Number of fence records at the start of kv_metas: 2 for a bounded page, else 0.
+    pub fn fence_count(&self) -> usize {
+        self.kv_metas.iter().take(2).take_while(|kv| kv.is_fence).count()
+    }
+
+    /// Indices in kv_metas of the key-value records, skipping the fences.
+    pub fn record_range(&self) -> Range<usize> {
+        self.fence_count()..self.kv_metas.len()
+    }
+
+    /// The page's fences; unbounded if it has no fence records.
+    pub fn fences(&self) -> Fences {
+        if self.fence_count() < 2 {
+            return Fences::default();
+        }
+        let high = self.key_at(1);
+        Fences::new(self.key_at(0), (!high.is_empty()).then_some(high))
+    }
+
+    /// True if key lies between the page's fences.
+    pub fn covers(&self, key: &[u8]) -> bool {
+        if self.fence_count() < 2 {
+            return true;
+        }
+        let high = self.key_at(1);
+        key >= self.key_at(0) && (high.is_empty() || key < high)
+    }
+
+    /// Replaces the fence records; unbounded fences remove them.
+    /// Returns false (leaving the page unchanged) if the fence keys do not fit.
+    pub fn set_fences(&mut self, fences: &Fences) -> bool {
+        let old_fences = self.fence_count();
+        if fences.is_unbounded() {
+            self.kv_metas.drain(..old_fences);
+            self.node_meta.record_count -= old_fences as u16;
+            return true;
+        }
+
+        let high = fences.high.as_deref().unwrap_or(&[]);
+        let total_size = (self.kv_metas.len() - old_fences + 2) * 8 + self.data.len() + fences.low.len() + high.len() + 12;
+        if total_size > self.node_meta.node_size as usize {
+            // The old fence keys become dead space too
+            let old_fence_bytes: usize = self.kv_metas[..old_fences].iter().map(|kv| kv.key_size as usize).sum();
+            if total_size - self.dead_bytes() - old_fence_bytes > self.node_meta.node_size as usize {
+                return false;
+            }
+            self.kv_metas.drain(..old_fences);
+            self.node_meta.record_count -= old_fences as u16;
+            self.compact();
+            return self.set_fences(fences);
+        }
+        let mut fence_metas = Vec::with_capacity(2);
+        let mut offset = self.data.len();
+        for key in [fences.low.as_slice(), high] {
+            match KVMeta::new(key.len(), 0, offset as u16, 0, true, 0, KVMeta::lookahead_of(key)) {
+                Ok(kv) => fence_metas.push(kv),
+                Err(_) => return false,
+            }
+            offset += key.len();
+        }
+        self.data.extend_from_slice(&fences.low);
+        self.data.extend_from_slice(high);
+        self.kv_metas.splice(..old_fences, fence_metas);
+        self.node_meta.record_count = self.kv_metas.len() as u16;
+        true
+    }
+
+    /// Compares the key of the record at idx with key, whose lookahead is given.
+    /// The data block is only read when the lookaheads tie.
+    fn cmp_key_at(&self, idx: usize, key: &[u8], lookahead: u16) -> Ordering {
+        match self.kv_metas[idx].lookahead.cmp(&lookahead) {
+            Ordering::Equal => self.key_at(idx).cmp(key),
+            order => order,
+        }
+    }
+
+    /// Returns the index in kv_metas of the record with target_key, if any.
+    /// Fence records are never matched.
+    pub fn find_index(&self, target_key: &[u8]) -> Option<usize> {
+        let lookahead = KVMeta::lookahead_of(target_key);
+        let mut left = self.fence_count();
         let mut right = self.kv_metas.len();
 
         while left < right {
             let mid = (left + right) / 2;
-            let mid_meta = &self.kv_metas[mid];
-
-            let key_start = mid_meta.offset as usize;
-            let key_end = key_start + mid_meta.key_size as usize;
 
-            let mid_key = &self.data[key_start..key_end];
-
-            match mid_key.cmp(target_key) {
-                Ordering::Equal => {
-                    let value_start = key_end;
-                    let value_end = value_start + mid_meta.value_size as usize;
-                    return Some(self.data[value_start..value_end].to_vec());
-                },
+            match self.cmp_key_at(mid, target_key, lookahead) {
+                Ordering::Equal => return Some(mid),
                 Ordering::Less => left = mid + 1,
                 Ordering::Greater => {
                     if mid == 0 { break; }
@@ -222,31 +371,146 @@ impl Page {
         None
     }
 
-    /// Inserts key-value while keeping KVMeta sorted.
+    /// Returns the key bytes of the record at idx.
+    pub fn key_at(&self, idx: usize) -> &[u8] {
+        let kv = &self.kv_metas[idx];
+        let key_start = kv.offset as usize;
+        let key_end = key_start + kv.key_size as usize;
+        &self.data[key_start..key_end]
+    }
+
+    /// Returns the value bytes of the record at idx.
+    pub fn value_at(&self, idx: usize) -> &[u8] {
+        let kv = &self.kv_metas[idx];
+        let value_start = kv.offset as usize + kv.key_size as usize;
+        let value_end = value_start + kv.value_size as usize;
+        &self.data[value_start..value_end]
+    }
+
+    /// Bytes the page would take up after inserting the record, or None if
+    /// the insert would leave the page unchanged.
+    fn size_after_insert(&self, key: &[u8], value: &[u8], record_type: RecordType) -> Option<usize> {
+        let used = 12 + self.kv_metas.len() * 8 + self.data.len(); // NodeMeta, KVMetas, data
+        match self.find_index(key) {
+            Some(idx) => {
+                let old = &self.kv_metas[idx];
+                if old.record_type().is_dirty() && !record_type.is_dirty() {
+                    return None; // a clean record never replaces a dirty one
+                }
+                if value.len() <= old.value_size as usize {
+                    Some(used) // overwritten in place
+                } else {
+                    Some(used + key.len() + value.len()) // relocated to the end of data
+                }
+            }
+            None => Some(used + 8 + key.len() + value.len()),
+        }
+    }
+
+    /// True if insert would succeed without a bigger page, compacting it if need be.
+    pub fn can_insert(&self, key: &[u8], value: &[u8], record_type: RecordType) -> bool {
+        let node_size = self.node_meta.node_size as usize;
+        self.size_after_insert(key, value, record_type)
+            .is_none_or(|size| size <= node_size || size - self.dead_bytes() <= node_size)
+    }
+
+    /// Bytes of the data block no record points at: values that were
+    /// relocated and records that were removed.
+    pub fn dead_bytes(&self) -> usize {
+        let live: usize = self.kv_metas.iter().map(|kv| kv.key_size as usize + kv.value_size as usize).sum();
+        self.data.len() - live
+    }
+
+    /// Rewrites the live records contiguously, in kv_metas order, and
+    /// updates their offsets. Returns the number of bytes reclaimed.
+    pub fn compact(&mut self) -> usize {
+        let dead = self.dead_bytes();
+        if dead == 0 {
+            return 0;
+        }
+        let mut data = Vec::with_capacity(self.data.len() - dead);
+        for kv in &mut self.kv_metas {
+            let start = kv.offset as usize;
+            let end = start + kv.key_size as usize + kv.value_size as usize;
+            kv.offset = data.len() as u16;
+            data.extend_from_slice(&self.data[start..end]);
+        }
+        self.data = data;
+        dead
+    }
+
+    /// Inserts key-value while keeping KVMeta sorted, replacing the record
+    /// of an equal key (upsert):
+    /// - a value no longer than the old one is overwritten in place; a longer
+    ///   one is appended and the old bytes stay behind as dead space
+    /// - when the record only fits once dead space is reclaimed, the page is
+    ///   compacted first
+    /// - the record takes the new type, e.g. a Cache record overwritten by an
+    ///   Insert becomes dirty; a clean (Cache or Phantom) record never replaces
+    ///   a dirty one, whose value the leaf does not hold yet, so that insert is a no-op
+    ///
+    /// Returns false, leaving the page unchanged, if there is no room.
     pub fn insert(&mut self, key: &[u8], value: &[u8], record_type: RecordType) -> bool {
-        let kv_meta_size = 8;
-        let total_size = self.kv_metas.len() * kv_meta_size + self.data.len() + key.len() + value.len() + 12; // NodeMeta size
+        // Records beyond the KVMeta size fields never fit
+        let mut new_kv = match KVMeta::new(key.len(), value.len(), 0, record_type.into(), false, 0, KVMeta::lookahead_of(key)) {
+            Ok(kv) => kv,
+            Err(_) => return false,
+        };
+        let size = match self.size_after_insert(key, value, record_type) {
+            Some(size) => size,
+            None => return true,
+        };
+        if size > self.node_meta.node_size as usize {
+            if size - self.dead_bytes() > self.node_meta.node_size as usize {
+                return false; // no space, even after compaction
+            }
+            self.compact();
+        }
 
-        if total_size > self.node_meta.node_size as usize {
-            return false; // no space
+        if let Some(idx) = self.find_index(key) {
+            let kv = &mut self.kv_metas[idx];
+            if value.len() <= kv.value_size as usize {
+                let value_start = kv.offset as usize + kv.key_size as usize;
+                self.data[value_start..value_start + value.len()].copy_from_slice(value);
+            } else {
+                kv.offset = self.data.len() as u16;
+                self.data.extend_from_slice(key);
+                self.data.extend_from_slice(value);
+            }
+            kv.value_size = value.len() as u16;
+            kv.type_flag = record_type.into();
+            return true;
         }
 
         // Append key and value data
-        let offset = self.data.len() as u16;
+        new_kv.offset = self.data.len() as u16;
         self.data.extend_from_slice(key);
         self.data.extend_from_slice(value);
+        let lookahead = new_kv.lookahead;
 
-        let new_kv = KVMeta::new(key.len() as u16, value.len() as u16, offset, record_type, false, 0, 0);
-
-        // Insert in sorted order
-        let pos = self.kv_metas.binary_search_by(|kv| {
+        // Insert in sorted order, after the fences
+        let fences = self.fence_count();
+        let pos = self.kv_metas[fences..].partition_point(|kv| {
             let k_start = kv.offset as usize;
             let k_end = k_start + kv.key_size as usize;
-            self.data[k_start..k_end].cmp(key)
-        }).unwrap_or_else(|e| e);
-        self.kv_metas.insert(pos, new_kv);
+            (kv.lookahead, &self.data[k_start..k_end]) < (lookahead, key)
+        });
+        self.kv_metas.insert(fences + pos, new_kv);
 
         self.node_meta.record_count += 1;
         true
     }
+
+    /// Removes the record with the given key, if present.
+    /// The key-value bytes stay in the data block as dead space.
+    pub fn remove(&mut self, key: &[u8]) -> bool {
+        match self.find_index(key) {
+            Some(idx) => {
+                self.kv_metas.remove(idx);
+                self.node_meta.record_count -= 1;
+                true
+            }
+            None => false,
+        }
+    }
 }
@@ -1,15 +1,30 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 pub struct PageIdAllocator {
-    next_id: usize,
+    next_id: AtomicUsize,
+    free_ids: Mutex<Vec<usize>>, // IDs of merged away leaves, handed out first
 }
 
 impl PageIdAllocator {
     pub fn new(start: usize) -> Self {
-        Self { next_id: start }
+        Self { next_id: AtomicUsize::new(start), free_ids: Mutex::new(Vec::new()) }
+    }
+
+    pub fn allocate(&self) -> usize {
+        if let Some(page_id) = self.free_ids.lock().unwrap().pop() {
+            return page_id;
+        }
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Returns a page ID that nothing refers to any more, for allocate to hand out again.
+    pub fn free(&self, page_id: usize) {
+        self.free_ids.lock().unwrap().push(page_id);
     }
 
-    pub fn allocate(&mut self) -> usize {
-        let id = self.next_id;
-        self.next_id += 1;
-        id
+    /// The ID the next call to allocate returns once no freed IDs are left.
+    pub fn next_id(&self) -> usize {
+        self.next_id.load(Ordering::SeqCst)
     }
 }
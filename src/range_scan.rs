@@ -0,0 +1,168 @@
+// src/range_scan.rs
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::ops::{Bound, RangeBounds};
+
+use crate::bf_tree::BfTree;
+use crate::error::Result;
+use crate::leaf_page::LeafPage;
+use crate::mini_page::MiniPage;
+use crate::page::{Page, RecordType};
+
+/// Ordered iterator over the key-value pairs of a BfTree within a key range.
+///
+/// Leaves are visited left to right. For each leaf, the sorted mini-page records
+/// are merged with the sorted leaf page records:
+/// - mini-page Insert records override the leaf value
+/// - mini-page Tombstone records hide the leaf record
+/// - Cache and Phantom records mirror the leaf, so they are skipped
+///
+/// Each leaf is read under its page latch, and the scan moves on from the
+/// leaf's high fence, so it keeps working while other threads split or merge
+/// leaves: records below the key a leaf was reached by were already returned.
+/// Every record is seen as of the moment its leaf was visited.
+///
+/// A leaf that cannot be read yields one Err item, after which the scan ends.
+pub struct RangeIter<'a> {
+    tree: &'a BfTree,
+    next_leaf: Option<Vec<u8>>, // a key routed to the next leaf to visit; None once done
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    buffered: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> RangeIter<'a> {
+    pub(crate) fn new<K: AsRef<[u8]>, R: RangeBounds<K>>(tree: &'a BfTree, range: R) -> Self {
+        let start = range.start_bound().map(|k| k.as_ref().to_vec());
+        let end = range.end_bound().map(|k| k.as_ref().to_vec());
+
+        // The empty key routes to the leftmost leaf
+        let next_leaf = match &start {
+            Bound::Included(s) | Bound::Excluded(s) => s.clone(),
+            Bound::Unbounded => Vec::new(),
+        };
+
+        Self {
+            tree,
+            next_leaf: Some(next_leaf),
+            start,
+            end,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    fn in_range(&self, key: &[u8]) -> bool {
+        let after_start = match &self.start {
+            Bound::Included(s) => key >= s.as_slice(),
+            Bound::Excluded(s) => key > s.as_slice(),
+            Bound::Unbounded => true,
+        };
+        after_start && !self.past_end(key)
+    }
+
+    fn past_end(&self, key: &[u8]) -> bool {
+        match &self.end {
+            Bound::Included(e) => key > e.as_slice(),
+            Bound::Excluded(e) => key >= e.as_slice(),
+            Bound::Unbounded => false,
+        }
+    }
+
+    /// Loads the leaf covering the next_leaf key and buffers its live records within the range.
+    fn fill_next_leaf(&mut self) -> Result<()> {
+        let key = match self.next_leaf.take() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let tree = self.tree;
+        tree.with_leaf(&key, |_, entry, fences| {
+            let leaf_page = LeafPage::load_from_disk(&tree.storage, entry.disk_offset)?;
+            // The leaf's high fence is where the next leaf starts; leaves
+            // written without fences fall back to the routing separators
+            let high_key = match leaf_page.page.fence_count() {
+                0 => fences.high.clone(),
+                _ => leaf_page.page.fences().high,
+            };
+            self.fill_from_leaf(&leaf_page, entry.mini_page.as_ref().map(|(_, m)| m), &key, high_key.as_deref());
+            Ok(())
+        })
+    }
+
+    /// Buffers the live records of one leaf from from_key on within the range,
+    /// and sets next_leaf to the leaf's high key unless the range ends in this leaf.
+    fn fill_from_leaf(&mut self, leaf_page: &LeafPage, mini_page: Option<&MiniPage>, from_key: &[u8], high_key: Option<&[u8]>) {
+        self.next_leaf = high_key.filter(|high_key| !self.past_end(high_key)).map(|k| k.to_vec());
+
+        let leaf = &leaf_page.page;
+        let mini = mini_page.map(|m| &m.page);
+
+        // Fence records are skipped
+        let mut li = leaf.fence_count();
+        let mut mi = mini.map_or(0, |m| m.fence_count());
+        let leaf_len = leaf.kv_metas.len();
+        let mini_len = mini.map_or(0, |m| m.kv_metas.len());
+
+        while li < leaf_len || mi < mini_len {
+            let order = match (li < leaf_len, mi < mini_len) {
+                (true, true) => leaf.key_at(li).cmp(mini.unwrap().key_at(mi)),
+                (true, false) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+
+            let record = match order {
+                Ordering::Less => {
+                    li += 1;
+                    Some((leaf.key_at(li - 1), leaf.value_at(li - 1)))
+                }
+                Ordering::Greater => {
+                    mi += 1;
+                    mini_record(mini.unwrap(), mi - 1, None)
+                }
+                Ordering::Equal => {
+                    li += 1;
+                    mi += 1;
+                    let leaf_record = (leaf.key_at(li - 1), leaf.value_at(li - 1));
+                    mini_record(mini.unwrap(), mi - 1, Some(leaf_record))
+                }
+            };
+
+            if let Some((key, value)) = record {
+                if self.past_end(key) {
+                    // Everything after this leaf is out of range too
+                    self.next_leaf = None;
+                    break;
+                }
+                if key >= from_key && self.in_range(key) {
+                    self.buffered.push_back((key.to_vec(), value.to_vec()));
+                }
+            }
+        }
+    }
+}
+
+/// Resolves a mini-page record against the leaf record with the same key (if any).
+fn mini_record<'p>(mini: &'p Page, idx: usize, leaf_record: Option<(&'p [u8], &'p [u8])>) -> Option<(&'p [u8], &'p [u8])> {
+    match mini.kv_metas[idx].record_type() {
+        RecordType::Insert => Some((mini.key_at(idx), mini.value_at(idx))),
+        RecordType::Tombstone => None,
+        RecordType::Cache | RecordType::Phantom => leaf_record,
+    }
+}
+
+impl Iterator for RangeIter<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.buffered.pop_front() {
+                return Some(Ok(record));
+            }
+            self.next_leaf.as_ref()?;
+            if let Err(e) = self.fill_next_leaf() {
+                self.next_leaf = None;
+                return Some(Err(e));
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+// src/storage.rs
+
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::BfTreeOptions;
+use crate::error;
+use crate::file_header::{FileHeader, FILE_HEADER_SIZE, FILE_HEADER_SLOTS};
+
+/// PageFile is the on-disk home of the leaf pages.
+/// - Page 0 holds the FileHeader slots; leaf pages follow.
+/// - Keeps a single file handle open for the lifetime of the tree.
+/// - Reads and writes whole pages at page-aligned offsets.
+/// - Hands out offsets for new leaf pages, reusing freed pages before growing the file.
+///
+/// A freed page may still be referenced by the current checkpoint, so it is
+/// only reused once the next checkpoint commits (see release_freed_pages).
+/// Every checkpoint records the pages that are free once it commits, which a
+/// reopened file starts from (see restore_free_pages).
+pub struct PageFile {
+    file: Mutex<File>,
+    header: RwLock<FileHeader>,
+    page_size: usize,
+    next_offset: AtomicU64,       // first unallocated page-aligned offset
+    free_pages: Mutex<Vec<u64>>,  // reusable page offsets
+    freed_pages: Mutex<Vec<u64>>, // freed since the last checkpoint; not reusable yet
+}
+
+impl PageFile {
+    /// Creates a new page file at path and writes its header.
+    /// Fails if the file already has contents.
+    pub fn create<P: AsRef<Path>>(path: P, options: &BfTreeOptions) -> error::Result<Self> {
+        options.validate()?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        if file.metadata()?.len() > 0 {
+            return Err(Error::new(ErrorKind::AlreadyExists, "file already holds a tree").into());
+        }
+
+        let header = FileHeader::from_options(options);
+        let page_size = options.leaf_page_size;
+        // The second slot stays empty until the first update
+        let mut header_page = header.serialize()?.to_vec();
+        let page_file = Self {
+            file: Mutex::new(file),
+            header: RwLock::new(header),
+            page_size,
+            next_offset: AtomicU64::new(page_size as u64),
+            free_pages: Mutex::new(Vec::new()),
+            freed_pages: Mutex::new(Vec::new()),
+        };
+
+        header_page.resize(page_size, 0);
+        page_file.write_page(0, &header_page)?;
+        Ok(page_file)
+    }
+
+    /// Opens an existing page file at path, reading the page size from its header.
+    /// A missing or invalid header is reported as error::Error::Corruption, one
+    /// of a newer format version as error::Error::UnsupportedVersion.
+    pub fn open<P: AsRef<Path>>(path: P) -> error::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        let mut header_bytes = [0u8; FILE_HEADER_SIZE * FILE_HEADER_SLOTS];
+        file.read_exact(&mut header_bytes).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => error::Error::Corruption("file is too short for a bftree header".to_string()),
+            _ => e.into(),
+        })?;
+        let header = FileHeader::read_slots(&header_bytes)?;
+
+        // Never hand out an offset that already holds data
+        let page_size = header.leaf_page_size as usize;
+        let len = file.metadata()?.len();
+        let next_offset = len.div_ceil(page_size as u64).max(1) * page_size as u64;
+
+        Ok(Self {
+            file: Mutex::new(file),
+            header: RwLock::new(header),
+            page_size,
+            next_offset: AtomicU64::new(next_offset),
+            free_pages: Mutex::new(Vec::new()),
+            freed_pages: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// A copy of the header read from (or written to) page 0.
+    pub fn header(&self) -> FileHeader {
+        self.header.read().unwrap().clone()
+    }
+
+    /// Size of every page in the file.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Current length of the file in bytes.
+    pub fn file_len(&self) -> Result<u64> {
+        Ok(self.file.lock().unwrap().metadata()?.len())
+    }
+
+    /// Reserves a page-aligned offset for a new leaf page: a free page if
+    /// there is one, else a fresh page at the end of the file.
+    pub fn allocate_page(&self) -> u64 {
+        if let Some(offset) = self.free_pages.lock().unwrap().pop() {
+            return offset;
+        }
+        self.allocate_pages(1)
+    }
+
+    /// Reserves count contiguous pages and returns the offset of the first:
+    /// a run of free pages if there is one, else fresh pages at the end of the file.
+    pub fn allocate_pages(&self, count: usize) -> u64 {
+        let mut free_pages = self.free_pages.lock().unwrap();
+        free_pages.sort_unstable();
+        let page_size = self.page_size as u64;
+        let run = free_pages.windows(count).position(|run| run[count - 1] - run[0] == (count as u64 - 1) * page_size);
+        if let Some(start) = run {
+            return free_pages.drain(start..start + count).next().unwrap();
+        }
+        self.next_offset.fetch_add(count as u64 * page_size, Ordering::SeqCst)
+    }
+
+    /// Returns the page at offset, which no longer holds a live leaf.
+    /// It is reused once release_freed_pages is called.
+    pub fn free_page(&self, offset: u64) {
+        self.freed_pages.lock().unwrap().push(offset);
+    }
+
+    /// Makes the pages freed so far reusable. Called once a checkpoint that
+    /// no longer refers to them has committed.
+    pub fn release_freed_pages(&self) {
+        let mut freed = std::mem::take(&mut *self.freed_pages.lock().unwrap());
+        self.free_pages.lock().unwrap().append(&mut freed);
+    }
+
+    /// The pages that are free once a checkpoint taken now commits: the free
+    /// pages and those freed since the last checkpoint, in offset order.
+    pub fn free_pages_at_checkpoint(&self) -> Vec<u64> {
+        let mut pages = self.free_pages.lock().unwrap().clone();
+        pages.extend(self.freed_pages.lock().unwrap().iter());
+        pages.sort_unstable();
+        pages
+    }
+
+    /// Makes pages reusable right away, e.g. the free pages of the checkpoint
+    /// the file was opened from.
+    pub fn restore_free_pages(&self, pages: impl IntoIterator<Item = u64>) {
+        self.free_pages.lock().unwrap().extend(pages);
+    }
+
+    /// First page offset not handed out yet.
+    pub fn allocated_end(&self) -> u64 {
+        self.next_offset.load(Ordering::SeqCst)
+    }
+
+    /// Number of pages freed since the last checkpoint, not reusable until the next.
+    pub fn freed_page_count(&self) -> usize {
+        self.freed_pages.lock().unwrap().len()
+    }
+
+    /// Number of pages allocate_page can hand out without growing the file.
+    pub fn free_page_count(&self) -> usize {
+        self.free_pages.lock().unwrap().len()
+    }
+
+    /// Makes header current: writes it, with the next generation, over the
+    /// older header slot and syncs it.
+    pub fn update_header(&self, header: FileHeader) -> error::Result<()> {
+        let mut current = self.header.write().unwrap();
+        let header = FileHeader { generation: current.generation + 1, ..header };
+        self.write_at(header.slot_offset(), &header.serialize()?)?;
+        self.sync()?;
+        *current = header;
+        Ok(())
+    }
+
+    /// Reads the page at offset. Bytes past the end of the file read as zeros,
+    /// so a page that was allocated but never written comes back empty.
+    pub fn read_page(&self, offset: u64) -> Result<Vec<u8>> {
+        self.check_aligned(offset)?;
+        let mut buffer = vec![0u8; self.page_size];
+
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match file.read(&mut buffer[filled..]) {
+                Ok(0) => break, // end of file
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Writes a full page at offset.
+    pub fn write_page(&self, offset: u64, buffer: &[u8]) -> Result<()> {
+        self.check_aligned(offset)?;
+        if buffer.len() != self.page_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page buffer is {} bytes, expected {}", buffer.len(), self.page_size),
+            ));
+        }
+
+        self.write_at(offset, buffer)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(buffer)
+    }
+
+    /// Flushes written pages to stable storage.
+    pub fn sync(&self) -> Result<()> {
+        self.file.lock().unwrap().sync_data()
+    }
+
+    fn check_aligned(&self, offset: u64) -> Result<()> {
+        if !offset.is_multiple_of(self.page_size as u64) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("page offset {} is not aligned to {}", offset, self.page_size),
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+// src/wal.rs
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::crc32c::{crc32c, crc32c_extend};
+use crate::error::{Error, Result};
+use crate::page::RecordType;
+
+pub const WAL_MAGIC: [u8; 8] = *b"BFTWAL\0\0";
+pub const WAL_HEADER_SIZE: u64 = 16; // magic + checkpoint LSN
+const WAL_RECORD_HEADER_SIZE: usize = 17; // lsn + type + key_len + value_len + crc
+const CRC_OFFSET: usize = 13; // the checksum follows lsn, type and lengths
+
+/// When appended WAL records are forced to stable storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    /// fsync after every insert or delete. Concurrent writers share one fsync.
+    PerOp,
+    /// fsync once max_batch records are pending, or when a commit finds the
+    /// oldest pending record older than max_delay.
+    GroupCommit { max_batch: usize, max_delay: Duration },
+    /// Never fsync; the OS writes the log back on its own schedule.
+    None,
+}
+
+/// One logged operation: an Insert (key, value) or a Tombstone (key).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    pub lsn: u64,
+    pub record_type: RecordType,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// Wal is the append-only write-ahead log of a BfTree.
+/// - Lives next to the page file, at "<path>.wal".
+/// - Starts with a header holding the LSN of the last checkpoint; every record
+///   after it must be replayed to rebuild the mini-pages lost in a crash.
+/// - A checkpoint (all mini-pages merged and the page file synced) empties the log.
+///
+/// Appends are serialized by an internal lock; an fsync runs outside it, so
+/// writers keep appending while earlier records are synced, and every record
+/// appended before the fsync started is covered by it.
+///
+/// Record layout: lsn u64, record type u8, key length u16, value length u16,
+/// CRC-32C u32, key, value. The checksum covers every other byte of the record.
+pub struct Wal {
+    state: Mutex<WalState>,
+    sync_file: File,        // second handle to the log, fsynced without holding state
+    sync_lock: Mutex<()>,   // one fsync at a time
+    synced_lsn: AtomicU64,  // every record up to this LSN is on stable storage
+    policy: WalSyncPolicy,
+}
+
+struct WalState {
+    file: File,
+    checkpoint_lsn: u64,
+    next_lsn: u64,
+    len: u64,                        // bytes of the log, header included
+    pending: usize,                  // records appended since the last fsync started
+    oldest_pending: Option<Instant>, // when the first of them was appended
+}
+
+impl Wal {
+    /// The WAL path for a page file at path.
+    pub fn path_for<P: AsRef<Path>>(path: P) -> PathBuf {
+        let mut wal_path = OsString::from(path.as_ref().as_os_str());
+        wal_path.push(".wal");
+        PathBuf::from(wal_path)
+    }
+
+    /// Creates an empty log at path, replacing any previous one.
+    pub fn create<P: AsRef<Path>>(path: P, policy: WalSyncPolicy) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut state = WalState {
+            file,
+            checkpoint_lsn: 0,
+            next_lsn: 1,
+            len: WAL_HEADER_SIZE,
+            pending: 0,
+            oldest_pending: None,
+        };
+        state.write_header()?;
+        state.file.sync_all()?;
+        Self::with_state(state, policy)
+    }
+
+    fn with_state(state: WalState, policy: WalSyncPolicy) -> Result<Self> {
+        Ok(Self {
+            sync_file: state.file.try_clone()?,
+            synced_lsn: AtomicU64::new(state.next_lsn - 1),
+            state: Mutex::new(state),
+            sync_lock: Mutex::new(()),
+            policy,
+        })
+    }
+
+    /// Opens the log at path and returns the records written after the last checkpoint.
+    /// A missing log is created empty. Reading stops at the first incomplete record
+    /// or record with a bad checksum (a write torn by a crash); the log is truncated there.
+    ///
+    /// Fails with Error::Corruption if a valid record follows the bad one, or a
+    /// valid record is out of sequence: the log was damaged, not torn.
+    pub fn open<P: AsRef<Path>>(path: P, policy: WalSyncPolicy) -> Result<(Self, Vec<WalRecord>)> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok((Self::create(path, policy)?, Vec::new()));
+        }
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        if bytes.len() < WAL_HEADER_SIZE as usize || bytes[..8] != WAL_MAGIC {
+            return Err(Error::Corruption(format!("{} is not a bftree WAL", path.display())));
+        }
+        let checkpoint_lsn = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+
+        let mut records = Vec::new();
+        let mut next_lsn = checkpoint_lsn + 1;
+        let mut end = WAL_HEADER_SIZE as usize;
+        while let Some((record, len)) = Self::parse_record(&bytes[end..]) {
+            if record.lsn < next_lsn {
+                // Logged before the checkpoint; the header was updated before truncating
+                end += len;
+                continue;
+            }
+            if record.lsn != next_lsn {
+                return Err(Error::Corruption(format!(
+                    "{}: record LSN {} at byte {} where LSN {} was expected",
+                    path.display(), record.lsn, end, next_lsn
+                )));
+            }
+            next_lsn += 1;
+            end += len;
+            records.push(record);
+        }
+        if let Some(offset) = Self::find_record(&bytes, end + 1, next_lsn) {
+            return Err(Error::Corruption(format!(
+                "{}: damaged record at byte {} is followed by a valid record at byte {}",
+                path.display(), end, offset
+            )));
+        }
+
+        let mut state = WalState {
+            file,
+            checkpoint_lsn,
+            next_lsn,
+            len: end as u64,
+            pending: 0,
+            oldest_pending: None,
+        };
+        state.file.set_len(end as u64)?;
+        state.file.seek(SeekFrom::End(0))?;
+        Ok((Self::with_state(state, policy)?, records))
+    }
+
+    /// Parses one record from the start of bytes, returning it with its encoded length.
+    /// Returns None if the record is incomplete or its checksum does not match.
+    fn parse_record(bytes: &[u8]) -> Option<(WalRecord, usize)> {
+        let mut cursor = Cursor::new(bytes);
+        let lsn = cursor.read_u64::<LittleEndian>().ok()?;
+        let record_type = RecordType::try_from(cursor.read_u8().ok()?).ok()?;
+        let key_len = cursor.read_u16::<LittleEndian>().ok()? as usize;
+        let value_len = cursor.read_u16::<LittleEndian>().ok()? as usize;
+        if !matches!(record_type, RecordType::Insert | RecordType::Tombstone) {
+            return None;
+        }
+
+        let len = WAL_RECORD_HEADER_SIZE + key_len + value_len;
+        if bytes.len() < len {
+            return None;
+        }
+        let stored = cursor.read_u32::<LittleEndian>().ok()?;
+        let crc = crc32c_extend(crc32c(&bytes[..CRC_OFFSET]), &bytes[WAL_RECORD_HEADER_SIZE..len]);
+        if stored != crc {
+            return None;
+        }
+        let key = bytes[WAL_RECORD_HEADER_SIZE..WAL_RECORD_HEADER_SIZE + key_len].to_vec();
+        let value = bytes[WAL_RECORD_HEADER_SIZE + key_len..len].to_vec();
+        Some((WalRecord { lsn, record_type, key, value }, len))
+    }
+
+    /// Offset of the first valid record at or after start with an LSN of at
+    /// least min_lsn. Only LSNs the rest of the log could reach are checksummed.
+    fn find_record(bytes: &[u8], start: usize, min_lsn: u64) -> Option<usize> {
+        (start..bytes.len()).find(|&offset| {
+            let rest = &bytes[offset..];
+            let max_lsn = min_lsn + (rest.len() / WAL_RECORD_HEADER_SIZE) as u64;
+            let plausible = rest
+                .get(..8)
+                .map(|lsn| u64::from_le_bytes(lsn.try_into().unwrap()))
+                .is_some_and(|lsn| (min_lsn..=max_lsn).contains(&lsn));
+            plausible && Self::parse_record(rest).is_some()
+        })
+    }
+
+    /// Appends an Insert or Tombstone record and returns its LSN.
+    /// The record is not durable until commit (or sync) covers it.
+    pub fn append(&self, record_type: RecordType, key: &[u8], value: &[u8]) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let lsn = state.next_lsn;
+        let buf = encode_record(lsn, record_type, key, value)?;
+        state.file.write_all(&buf)?;
+        state.next_lsn += 1;
+        state.len += buf.len() as u64;
+
+        state.pending += 1;
+        state.oldest_pending.get_or_insert_with(Instant::now);
+        Ok(lsn)
+    }
+
+    /// Syncs the record with the given LSN according to the policy.
+    pub fn commit(&self, lsn: u64) -> Result<()> {
+        let sync_now = match self.policy {
+            WalSyncPolicy::PerOp => true,
+            WalSyncPolicy::GroupCommit { max_batch, max_delay } => {
+                let state = self.state.lock().unwrap();
+                state.pending >= max_batch || state.oldest_pending.is_some_and(|t| t.elapsed() >= max_delay)
+            }
+            WalSyncPolicy::None => false,
+        };
+        if sync_now {
+            self.sync_to(lsn)?;
+        }
+        Ok(())
+    }
+
+    /// Forces every appended record to stable storage.
+    pub fn sync(&self) -> Result<()> {
+        let last_lsn = self.state.lock().unwrap().next_lsn - 1;
+        self.sync_to(last_lsn)
+    }
+
+    /// Makes sure every record up to lsn is on stable storage.
+    /// A writer that finds an fsync in progress waits for it and reuses its result.
+    fn sync_to(&self, lsn: u64) -> Result<()> {
+        if self.synced_lsn.load(Ordering::Acquire) >= lsn {
+            return Ok(());
+        }
+        let _sync = self.sync_lock.lock().unwrap();
+        if self.synced_lsn.load(Ordering::Acquire) >= lsn {
+            return Ok(());
+        }
+
+        let target = {
+            let mut state = self.state.lock().unwrap();
+            state.pending = 0;
+            state.oldest_pending = None;
+            state.next_lsn - 1
+        };
+        self.sync_file.sync_data()?;
+        self.synced_lsn.fetch_max(target, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Marks every record appended so far as applied to the page file and empties the log.
+    /// The caller must have synced the page file first, and keep writers out until it returns.
+    pub fn checkpoint(&self) -> Result<()> {
+        let _sync = self.sync_lock.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+
+        // The new checkpoint LSN is durable before any record is dropped
+        state.checkpoint_lsn = state.next_lsn - 1;
+        state.write_header()?;
+        state.file.sync_data()?;
+
+        state.file.set_len(WAL_HEADER_SIZE)?;
+        state.file.seek(SeekFrom::End(0))?;
+        state.file.sync_all()?;
+        state.len = WAL_HEADER_SIZE;
+        state.pending = 0;
+        state.oldest_pending = None;
+        self.synced_lsn.store(state.checkpoint_lsn, Ordering::Release);
+        Ok(())
+    }
+
+    /// LSN of the last checkpoint; records up to it are already in the page file.
+    pub fn checkpoint_lsn(&self) -> u64 {
+        self.state.lock().unwrap().checkpoint_lsn
+    }
+
+    /// LSN the next appended record will get.
+    pub fn next_lsn(&self) -> u64 {
+        self.state.lock().unwrap().next_lsn
+    }
+
+    /// Current length of the log in bytes.
+    pub fn len(&self) -> u64 {
+        self.state.lock().unwrap().len
+    }
+
+    /// True if no record was appended since the last checkpoint.
+    pub fn is_empty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.next_lsn == state.checkpoint_lsn + 1
+    }
+}
+
+/// Encodes a record in the log's record layout.
+fn encode_record(lsn: u64, record_type: RecordType, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(WAL_RECORD_HEADER_SIZE + key.len() + value.len());
+    buf.write_u64::<LittleEndian>(lsn)?;
+    buf.write_u8(record_type.into())?;
+    buf.write_u16::<LittleEndian>(key.len() as u16)?;
+    buf.write_u16::<LittleEndian>(value.len() as u16)?;
+    let crc = crc32c_extend(crc32c_extend(crc32c(&buf), key), value);
+    buf.write_u32::<LittleEndian>(crc)?;
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    Ok(buf)
+}
+
+impl WalState {
+    fn write_header(&mut self) -> Result<()> {
+        let mut header = Vec::with_capacity(WAL_HEADER_SIZE as usize);
+        header.extend_from_slice(&WAL_MAGIC);
+        header.write_u64::<LittleEndian>(self.checkpoint_lsn)?;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
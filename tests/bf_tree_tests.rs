@@ -1,5 +1,6 @@
-use bftree::{BfTree, InnerNode, MappingTable, MiniPage};
-use std::collections::HashMap;
+use bftree::{crc32c, BfTree, BfTreeOptions, BufferPool, Error, FileHeader, FILE_HEADER_SIZE, FORMAT_VERSION, LeafPage, MappingTable, MAX_PAGE_IDS, PageFile, RangeIter};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use log::{info, debug};
 mod test_util;
 
@@ -7,71 +8,530 @@ mod test_util;
 fn test_get() {
     info!("[TEST] bf_tree::get()");
 
-    // Setup root inner node
-    let mut root = InnerNode::new();
-    root.keys.push(vec![50]);
-    root.children.push(1);
-    root.children.push(2);
-    debug!("[Setup] Root InnerNode");
-    debug!("keys     = {:?}", root.keys);
-    debug!("children = {:?}", root.children);
-
-    // Setup inner node at page_id=1
-    let mut layer1 = InnerNode::new();
-    layer1.keys.push(vec![10]);
-    layer1.children.push(3);
-    layer1.children.push(4);
-    debug!("[Setup] InnerNode page_id=1");
-    debug!("keys     = {:?}", layer1.keys);
-    debug!("children = {:?}", layer1.children);
-
-    // Inner nodes map
-    let mut inner_nodes = HashMap::new();
-    inner_nodes.insert(1, layer1);
-
-    // Mapping table setup 
-    let mut mapping_table = MappingTable::new(5);
-    mapping_table.insert(3, None, 3000); // page_id=3 ➔ leaf only
-    
-    let mut dummy_mini_page = MiniPage::new(4000);
-    let key2 = vec![15];
-    let value2 = b"value_15".to_vec();
-    dummy_mini_page.insert(&key2, &value2);
-    mapping_table.insert(4, Some(dummy_mini_page.clone()), 4000); // page_id=4 ➔ mini-page + leaf
-
-    debug!("[Setup] Mapping table entries:");
-    for page_id in 3..5 {
-        match mapping_table.get(page_id) {
-            Some((mini_page_opt, disk_offset)) => {
-                debug!(
-                    "page_id={:<2} | mini_page_present={:<5} | disk_offset={}",
-                    page_id,
-                    mini_page_opt.is_some(),
-                    disk_offset
-                );
-            }
-            None => debug!("page_id={:<2} | <empty>", page_id),
-        }
-    }
-
-    // Build BfTree
-    let tree = BfTree {
-        mapping_table,
-        root_inner_node: root,
-        inner_nodes,
+    let options = BfTreeOptions::builder().cache_probability(0.0).build().unwrap();
+    let tree = BfTree::create(test_util::temp_path("get.bftree"), options).unwrap();
+    let value_of = |i: u32| format!("value_{}", i).into_bytes();
+
+    // Odd keys are pushed down to the leaf pages, key 15 then stays in a mini-page
+    for i in (1..200u32).step_by(2) {
+        tree.insert(&i.to_be_bytes(), &value_of(i)).unwrap();
+    }
+    tree.run_eviction(0).unwrap();
+    tree.insert(&15u32.to_be_bytes(), b"value_15_mini").unwrap();
+
+    debug!("[Setup] Mapping entries:");
+    for i in [5u32, 15, 151] {
+        let (mini_page_addr, disk_offset, page_id) = tree.traverse(&i.to_be_bytes()).unwrap();
+        debug!(
+            "key={:<3} | page_id={:<2} | mini_page_present={:<5} | disk_offset={}",
+            i,
+            page_id,
+            mini_page_addr.is_some(),
+            disk_offset
+        );
+    }
+    assert!(tree.traverse(&15u32.to_be_bytes()).unwrap().0.is_some());
+
+    // Scenario 1: key=5 lives in the leaf page only
+    assert_eq!(tree.get(&5u32.to_be_bytes()).unwrap(), Some(value_of(5)));
+
+    // Scenario 2: key=15 is found in the mini-page before the leaf page
+    assert_eq!(tree.get(&15u32.to_be_bytes()).unwrap(), Some(b"value_15_mini".to_vec()));
+
+    // Scenario 3: even keys were never inserted
+    assert!(tree.get(&4u32.to_be_bytes()).unwrap().is_none());
+    assert!(tree.get(&500u32.to_be_bytes()).unwrap().is_none());
+
+    info!("[TEST] All bf_tree::get() assertions passed");
+}
+
+#[test]
+fn test_delete() {
+    info!("[TEST] bf_tree::delete()");
+
+    let tree = BfTree::create(test_util::temp_path("delete.bftree"), BfTreeOptions::default()).unwrap();
+    let leaf_of = |tree: &BfTree, key: &[u8]| {
+        let (_, leaf_offset, _) = tree.traverse(key).unwrap();
+        LeafPage::load_from_disk(tree.storage(), leaf_offset).unwrap()
     };
 
-    // Scenario 1: key=5
-    let key1 = vec![5];
-    let result1 = tree.get(&key1);
-    assert!(result1.is_none());
+    // Delete of a key that only lives in the mini-page
+    tree.insert(b"apple", b"red").unwrap();
+    tree.delete(b"apple").unwrap();
+    assert!(tree.get(b"apple").unwrap().is_none());
 
-    // Scenario 2: key=15
-    let result2 = tree.get(&key2);
-    assert!(result2.is_some());
-    assert_eq!(result2.unwrap(), value2);
+    // Push two keys down to the leaf page
+    tree.insert(b"apple", b"red").unwrap();
+    tree.insert(b"banana", b"yellow").unwrap();
+    tree.run_eviction(0).unwrap();
+    assert_eq!(leaf_of(&tree, b"apple").binary_search(b"apple"), Some(b"red".to_vec()));
 
-    info!("[TEST] All bf_tree::traverse() assertions passed");
+    // The tombstone must hide the leaf record before any merge happens
+    tree.delete(b"apple").unwrap();
+    assert!(tree.get(b"apple").unwrap().is_none());
+    assert_eq!(tree.get(b"banana").unwrap(), Some(b"yellow".to_vec()));
+    assert_eq!(leaf_of(&tree, b"apple").binary_search(b"apple"), Some(b"red".to_vec()));
+
+    // Merging the tombstone removes the key from the leaf page
+    tree.run_eviction(0).unwrap();
+    let leaf = leaf_of(&tree, b"apple");
+    debug!("leaf record_count after merge = {}", leaf.page.node_meta.record_count);
+    assert!(leaf.binary_search(b"apple").is_none());
+    assert_eq!(leaf.binary_search(b"banana"), Some(b"yellow".to_vec()));
+    assert!(tree.get(b"apple").unwrap().is_none());
+
+    info!("[TEST] All bf_tree::delete() assertions passed");
 }
 
+#[test]
+fn test_range() {
+    info!("[TEST] bf_tree::range()");
+
+    // Every lookup caches its result, so scans also meet Cache and Phantom records
+    let options = BfTreeOptions::builder().cache_probability(1.0).build().unwrap();
+    let tree = BfTree::create(test_util::temp_path("range.bftree"), options).unwrap();
+    let key_of = |i: u32| format!("key-{:04}", i).into_bytes();
+    let (leaf, mini) = (vec![b'l'; 100], vec![b'm'; 100]);
+    let mut expected = BTreeMap::new();
+
+    // Leaf pages hold every third key
+    for i in (0..600).step_by(3) {
+        tree.insert(&key_of(i), &leaf).unwrap();
+        expected.insert(key_of(i), leaf.clone());
+    }
+    tree.run_eviction(0).unwrap();
+    let leaves = tree.mapping_table().entries().count();
+    assert!(leaves > 2, "expected several leaves, found {}", leaves);
+
+    // Mini-pages on top: overwrite, delete, add, cache and phantom records
+    for i in (0..600).step_by(30) {
+        tree.insert(&key_of(i), &mini).unwrap();
+        expected.insert(key_of(i), mini.clone());
+        tree.delete(&key_of(i + 3)).unwrap();
+        expected.remove(&key_of(i + 3));
+        tree.insert(&key_of(i + 1), &mini).unwrap();
+        expected.insert(key_of(i + 1), mini.clone());
+        assert_eq!(tree.get(&key_of(i + 6)).unwrap(), Some(leaf.clone()));
+        assert!(tree.get(&key_of(i + 2)).unwrap().is_none());
+    }
+
+    let all: Vec<_> = tree.iter().collect::<Result<_, _>>().unwrap();
+    debug!("full scan = {} records", all.len());
+    assert_eq!(all, expected.clone().into_iter().collect::<Vec<_>>());
+
+    // Bounded scans across leaf boundaries
+    let keys = |scan: RangeIter| scan.map(|r| r.unwrap().0).collect::<Vec<_>>();
+    let (low, high) = (key_of(100), key_of(400));
+    assert_eq!(keys(tree.range(low.clone()..high.clone())), expected.range(low.clone()..high.clone()).map(|(k, _)| k.clone()).collect::<Vec<_>>());
+    assert_eq!(keys(tree.range(low.clone()..=high.clone())), expected.range(low..=high).map(|(k, _)| k.clone()).collect::<Vec<_>>());
+    assert_eq!(tree.range(key_of(600)..).count(), 0);
+
+    info!("[TEST] All bf_tree::range() assertions passed");
+}
+
+#[test]
+fn test_page_file() {
+    info!("[TEST] storage::PageFile");
+
+    let path = test_util::temp_path("page_file.bftree");
+    let storage = PageFile::create(&path, &BfTreeOptions::default()).unwrap();
+    assert!(PageFile::create(&path, &BfTreeOptions::default()).is_err());
+
+    // Page 0 is the header; offsets are handed out page by page after it
+    let first = storage.allocate_page();
+    let second = storage.allocate_page();
+    assert_eq!(first, 4096);
+    assert_eq!(second, 8192);
+
+    // Allocated but unwritten pages read back as zeros, which is no valid leaf
+    assert!(storage.read_page(second).unwrap().iter().all(|&b| b == 0));
+    assert!(matches!(LeafPage::load_from_disk(&storage, second), Err(Error::Corruption(_))));
 
+    let mut leaf = LeafPage::new();
+    leaf.insert(b"key", b"value");
+    leaf.flush_to_disk(&storage, second).unwrap();
+    assert_eq!(LeafPage::load_from_disk(&storage, second).unwrap().binary_search(b"key"), Some(b"value".to_vec()));
+
+    // Misaligned offsets and short buffers are rejected
+    assert!(storage.read_page(100).is_err());
+    assert!(storage.write_page(first, &[0u8; 16]).is_err());
+
+    // Reopening resumes allocation after the existing pages
+    drop(storage);
+    let storage = PageFile::open(&path).unwrap();
+    assert_eq!(storage.header(), FileHeader::from_options(&BfTreeOptions::default()));
+    assert_eq!(storage.allocate_page(), 12288);
+    assert_eq!(LeafPage::load_from_disk(&storage, second).unwrap().binary_search(b"key"), Some(b"value".to_vec()));
+
+    // Files without a bftree header are refused
+    std::fs::write(&path, vec![0xAB; 8192]).unwrap();
+    assert!(PageFile::open(&path).is_err());
+
+    info!("[TEST] All storage::PageFile assertions passed");
+}
+
+#[test]
+fn test_file_header() {
+    info!("[TEST] file_header::FileHeader slots and versions");
+
+    let path = test_util::temp_path("file_header.bftree");
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+    for i in 0..100u32 {
+        tree.insert(&i.to_be_bytes(), b"value").unwrap();
+    }
+
+    // Updates alternate between the two slots of page 0
+    let generation = tree.storage().header().generation;
+    tree.checkpoint().unwrap();
+    tree.checkpoint().unwrap();
+    let header = tree.storage().header();
+    assert_eq!(header.generation, generation + 2);
+    assert_eq!(header.format_version, FORMAT_VERSION);
+    let page = tree.storage().read_page(0).unwrap();
+    let slots: Vec<FileHeader> = page.chunks(FILE_HEADER_SIZE).take(2).map(|s| FileHeader::deserialize(s).unwrap()).collect();
+    assert_eq!(slots[header.slot_offset() as usize / FILE_HEADER_SIZE], header);
+    assert_eq!(FileHeader::read_slots(&page).unwrap(), header);
+
+    // A torn write of the current slot falls back to the other one
+    let mut torn = page.clone();
+    torn[header.slot_offset() as usize + 20] ^= 0xFF;
+    tree.storage().write_page(0, &torn).unwrap();
+    drop(tree);
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    assert_eq!(tree.storage().header().generation, header.generation - 1);
+    assert_eq!(tree.get(&7u32.to_be_bytes()).unwrap(), Some(b"value".to_vec()));
+
+    // A damaged version field fails the checksum rather than being read as another format
+    tree.checkpoint().unwrap();
+    let header = tree.storage().header();
+    let page = tree.storage().read_page(0).unwrap();
+    let current = header.slot_offset() as usize;
+    let older = FILE_HEADER_SIZE - current;
+    let mut damaged = page.clone();
+    for version in [0u32, FORMAT_VERSION + 1, 600] {
+        damaged[current + 8..current + 12].copy_from_slice(&version.to_le_bytes());
+        let result = FileHeader::deserialize(&damaged[current..current + FILE_HEADER_SIZE]);
+        assert!(matches!(result, Err(Error::Corruption(_))), "version {}: {:?}", version, result);
+    }
+    tree.storage().write_page(0, &damaged).unwrap();
+    drop(tree);
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    assert_eq!(tree.storage().header().generation, header.generation - 1);
+    assert_eq!(tree.iter().count(), 100);
+
+    // A newer format version is refused, even in the older slot
+    let mut newer = page.clone();
+    newer[older + 8..older + 12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+    let checksum = crc32c(&newer[older..older + FILE_HEADER_SIZE - 4]);
+    newer[older + FILE_HEADER_SIZE - 4..older + FILE_HEADER_SIZE].copy_from_slice(&checksum.to_le_bytes());
+    tree.storage().write_page(0, &newer).unwrap();
+    drop(tree);
+    let result = BfTree::open(&path, BfTreeOptions::default());
+    assert!(
+        matches!(result, Err(Error::UnsupportedVersion { version, supported: FORMAT_VERSION }) if version == FORMAT_VERSION + 1),
+        "{:?}",
+        result.err()
+    );
+
+    info!("[TEST] All file_header::FileHeader slots and versions assertions passed");
+}
+
+#[test]
+fn test_split_propagation() {
+    info!("[TEST] bf_tree leaf and inner node splits");
+
+    let options = BfTreeOptions::builder().buffer_pool_size(64 * 1024).build().unwrap();
+    let tree = BfTree::create(test_util::temp_path("split.bftree"), options).unwrap();
+
+    // Large values force hundreds of leaf splits, enough to overflow the root
+    let count: u32 = 5000;
+    let key_of = |i: u32| i.to_be_bytes().to_vec();
+    let value_of = |i: u32| vec![(i % 251) as u8; 400];
+    for n in 0..count {
+        let i = (n * 7919) % count; // scrambled insert order
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+
+    // Push every buffered record down to the leaves
+    tree.run_eviction(0).unwrap();
+    assert!(tree.buffer_pool().lock().unwrap().is_empty());
+
+    let inner_nodes = tree.inner().to_nodes();
+    debug!("root keys = {}, inner nodes = {}", inner_nodes[0].1.keys.len(), inner_nodes.len());
+    assert!(inner_nodes.len() >= 3, "root should have split into a new level");
+    assert!(inner_nodes.iter().all(|(_, node)| !node.is_overfull(tree.options().inner_node_size)));
+
+    // Every key is reachable through the new separators, in order
+    for i in 0..count {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+    }
+    let keys: Vec<_> = tree.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, (0..count).map(key_of).collect::<Vec<_>>());
+
+    // The leaves' fences tile the key space and agree with the inner nodes
+    let mut fences: Vec<_> = tree
+        .mapping_table()
+        .entries()
+        .map(|(page_id, (_, disk_offset))| {
+            let leaf = LeafPage::load_from_disk(tree.storage(), disk_offset).unwrap();
+            assert!(leaf.records().iter().all(|(k, _)| leaf.page.covers(k)));
+            (leaf.page.fences(), page_id)
+        })
+        .collect();
+    fences.sort_by(|a, b| a.0.low.cmp(&b.0.low));
+    assert!(fences[0].0.low.is_empty() && fences.last().unwrap().0.high.is_none());
+    for pair in fences.windows(2) {
+        assert_eq!(pair[0].0.high.as_ref(), Some(&pair[1].0.low));
+    }
+    for (leaf_fences, page_id) in &fences {
+        assert_eq!(tree.traverse(&leaf_fences.low).unwrap().2, *page_id);
+    }
+
+    info!("[TEST] All split propagation assertions passed");
+}
+
+#[test]
+fn test_leaf_merge() {
+    info!("[TEST] bf_tree leaf merge and borrow on underflow");
+
+    let path = test_util::temp_path("merge.bftree");
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let value_of = |i: u32| vec![(i % 251) as u8; 100];
+    let leaf_fences = |tree: &BfTree| {
+        let mut fences: Vec<_> = tree
+            .mapping_table()
+            .entries()
+            .map(|(_, (_, disk_offset))| LeafPage::load_from_disk(tree.storage(), disk_offset).unwrap().page.fences())
+            .collect();
+        fences.sort_by(|a, b| a.low.cmp(&b.low));
+        fences
+    };
+
+    for i in 0..3000 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    tree.checkpoint().unwrap();
+    let before = leaf_fences(&tree);
+
+    // Emptying most of one leaf makes it borrow from its fuller neighbour
+    let target = &before[before.len() / 2];
+    let doomed: Vec<u32> = (0..3000).filter(|&i| target.contains(&key_of(i))).skip(2).collect();
+    for &i in &doomed {
+        tree.delete(&key_of(i)).unwrap();
+    }
+    tree.checkpoint().unwrap();
+    let after = leaf_fences(&tree);
+    assert_eq!(after.len(), before.len());
+    let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b.low != a.low).collect();
+    assert_eq!(moved.len(), 1, "only the separator between the two leaves moves");
+    assert!(after.iter().all(|f| f.low == target.low || !target.contains(&f.low)));
+
+    // Deleting nine keys in ten leaves most leaves underfull, so they merge
+    let live = |i: u32| i.is_multiple_of(10) && !doomed.contains(&i);
+    for i in (0..3000).filter(|&i| i % 10 != 0 && !doomed.contains(&i)) {
+        tree.delete(&key_of(i)).unwrap();
+    }
+    let next_page_id = tree.page_id_allocator().next_id();
+    let file_pages = tree.storage().file_len().unwrap() / tree.storage().page_size() as u64;
+    tree.checkpoint().unwrap();
+    let merged = leaf_fences(&tree);
+    debug!("leaves: {} before, {} after merging", after.len(), merged.len());
+    assert!(merged.len() * 3 < after.len());
+    assert_eq!(tree.inner().get(0).unwrap().keys.len() + 1, merged.len());
+    assert!(merged[0].low.is_empty() && merged.last().unwrap().high.is_none());
+    for pair in merged.windows(2) {
+        assert_eq!(pair[0].high.as_ref(), Some(&pair[1].low));
+    }
+    for i in 0..3000 {
+        let expected = live(i).then(|| value_of(i));
+        assert_eq!(tree.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+
+    // Freed page IDs and pages are handed out again before new ones
+    assert!(tree.page_id_allocator().allocate() < next_page_id);
+    assert!(tree.storage().free_page_count() > 0);
+    assert!(tree.storage().allocate_page() < file_pages * tree.storage().page_size() as u64);
+
+    // Reopening finds the page IDs freed before the checkpoint
+    tree.close().unwrap();
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    assert!(tree.page_id_allocator().allocate() < tree.page_id_allocator().next_id());
+    let keys: Vec<_> = tree.iter().map(|r| r.unwrap().0).collect();
+    assert_eq!(keys, (0..3000).filter(|&i| live(i)).map(key_of).collect::<Vec<_>>());
+
+    info!("[TEST] All leaf merge assertions passed");
+}
+
+#[test]
+fn test_create_open_close() {
+    info!("[TEST] bf_tree create/open/close");
+
+    let path = test_util::temp_path("lifecycle.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let value_of = |i: u32| vec![b'v'; 100 + (i % 50) as usize];
+
+    // A new tree is empty; creating over an existing tree is refused
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+    assert!(tree.get(b"missing").unwrap().is_none());
+    assert!(BfTree::create(&path, BfTreeOptions::default()).is_err());
+
+    // Enough data to split leaves, plus a few deletes
+    for i in 0..2000 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    for i in (0..2000).step_by(10) {
+        tree.delete(&key_of(i)).unwrap();
+    }
+    tree.close().unwrap();
+
+    // Reopening restores the inner nodes from the checkpoint written by close
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    debug!("reopened with {} separators in the root", tree.inner().get(0).unwrap().keys.len());
+    for i in 0..2000 {
+        let expected = if i % 10 == 0 { None } else { Some(value_of(i)) };
+        assert_eq!(tree.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+    assert_eq!(tree.iter().count(), 1800);
+
+    // The reopened tree keeps working, including new splits
+    for i in 2000..3000 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    tree.close().unwrap();
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    assert_eq!(tree.iter().count(), 2800);
+
+    // Opening a file that holds no tree fails
+    assert!(BfTree::open(test_util::temp_path("lifecycle-missing.bftree"), BfTreeOptions::default()).is_err());
+
+    info!("[TEST] All bf_tree create/open/close assertions passed");
+}
+
+#[test]
+fn test_options() {
+    info!("[TEST] bf_tree options");
+
+    // Invalid combinations are rejected by the builder
+    assert!(BfTreeOptions::builder().leaf_page_size(3000).build().is_err());
+    assert!(BfTreeOptions::builder().leaf_page_size(64 * 1024).build().is_err());
+    assert!(BfTreeOptions::builder().mini_page_min_size(8192).build().is_err());
+    assert!(BfTreeOptions::builder().mini_page_max_size(8192).build().is_err());
+    assert!(BfTreeOptions::builder().buffer_pool_size(1024).build().is_err());
+    assert!(BfTreeOptions::builder().cache_probability(1.5).build().is_err());
+
+    // A tree with 16 KiB leaves and no read caching
+    let options = BfTreeOptions::builder()
+        .leaf_page_size(16 * 1024)
+        .mini_page_max_size(16 * 1024)
+        .inner_node_size(8 * 1024)
+        .cache_probability(0.0)
+        .build()
+        .unwrap();
+    let path = test_util::temp_path("options.bftree");
+    let tree = BfTree::create(&path, options.clone()).unwrap();
+    for i in 0..3000u32 {
+        tree.insert(&i.to_be_bytes(), &[7u8; 64]).unwrap();
+    }
+    for i in 0..3000u32 {
+        assert_eq!(tree.get(&i.to_be_bytes()).unwrap(), Some(vec![7u8; 64]));
+    }
+    tree.close().unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len() % (16 * 1024), 0);
+
+    // Sizes come from the file header; runtime settings from the caller
+    let runtime = BfTreeOptions::builder().buffer_pool_size(256 * 1024).build().unwrap();
+    let tree = BfTree::open(&path, runtime).unwrap();
+    assert_eq!(tree.options().leaf_page_size, 16 * 1024);
+    assert_eq!(tree.options().mini_page_max_size, 16 * 1024);
+    assert_eq!(tree.options().buffer_pool_size, 256 * 1024);
+    assert_eq!(tree.get(&2999u32.to_be_bytes()).unwrap(), Some(vec![7u8; 64]));
+    assert_eq!(tree.iter().count(), 3000);
+    drop(tree);
+
+    // With 32 KiB pages, values are still bounded by the 14-bit KVMeta value_size
+    let options = BfTreeOptions::builder()
+        .leaf_page_size(32 * 1024)
+        .mini_page_max_size(32 * 1024)
+        .inner_node_size(16 * 1024)
+        .build()
+        .unwrap();
+    let tree = BfTree::create(test_util::temp_path("options-32k.bftree"), options).unwrap();
+    let result = tree.insert(b"big", &[1u8; 20000]);
+    assert!(matches!(result, Err(Error::KeyTooLarge { .. })), "{:?}", result);
+    assert!(tree.get(b"big").unwrap().is_none());
+    tree.insert(b"large", &[2u8; 16000]).unwrap();
+    tree.run_eviction(0).unwrap();
+    assert_eq!(tree.get(b"large").unwrap(), Some(vec![2u8; 16000]));
+
+    info!("[TEST] All bf_tree options assertions passed");
+}
+
+#[test]
+fn test_errors() {
+    info!("[TEST] bf_tree errors");
+
+    let path = test_util::temp_path("errors.bftree");
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+
+    // Records that cannot fit a mini-page are refused, not buffered
+    let result = tree.insert(b"big", &[0u8; 8192]);
+    assert!(matches!(result, Err(Error::KeyTooLarge { max_size: 4096, .. })), "{:?}", result);
+    assert!(tree.get(b"big").unwrap().is_none());
+    let result = tree.insert(&[b'k'; 600], b"v");
+    assert!(matches!(result, Err(Error::KeyTooLarge { .. })), "keys must leave room for fences: {:?}", result);
+
+    for i in 0..100u32 {
+        tree.insert(&i.to_be_bytes(), b"value").unwrap();
+    }
+    tree.run_eviction(0).unwrap();
+    let (_, leaf_offset, _) = tree.traverse(&0u32.to_be_bytes()).unwrap();
+
+    // A record count that runs past the page is corruption, not a panic
+    let mut bytes = LeafPage::load_from_disk(tree.storage(), leaf_offset).unwrap().to_bytes().unwrap();
+    bytes[4..6].copy_from_slice(&u16::MAX.to_le_bytes());
+    tree.storage().write_page(leaf_offset, &bytes).unwrap();
+
+    let result = tree.get(&7u32.to_be_bytes());
+    debug!("get on corrupt leaf = {:?}", result);
+    assert!(matches!(result, Err(Error::Corruption(_))));
+    let scan: Vec<_> = tree.iter().collect();
+    assert_eq!(scan.len(), 1);
+    assert!(matches!(scan[0], Err(Error::Corruption(_))));
+    assert!(matches!(tree.insert(b"k", b"v").and_then(|_| tree.run_eviction(0)), Err(Error::Corruption(_))));
+    drop(tree);
+
+    // After reopening, the corrupt leaf is reported once it is read again
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    assert!(matches!(tree.get(b"missing"), Err(Error::Corruption(_))));
+
+    // A zeroed leaf is corruption too, not an empty page
+    let (_, leaf_offset, _) = tree.traverse(&0u32.to_be_bytes()).unwrap();
+    tree.storage().write_page(leaf_offset, &vec![0; tree.storage().page_size()]).unwrap();
+    assert!(matches!(tree.get(b"missing"), Err(Error::Corruption(_))));
+    drop(tree);
+
+    // Invalid options and headers get their own variants
+    let result = BfTreeOptions::builder().leaf_page_size(1000).build();
+    assert!(matches!(result, Err(Error::InvalidOptions(_))));
+    let result = BfTreeOptions::builder().inner_node_size(256).build();
+    assert!(matches!(result, Err(Error::InvalidOptions(_))), "inner nodes must hold 3 of the longest keys: {:?}", result);
+    std::fs::write(&path, b"not a tree").unwrap();
+    assert!(matches!(PageFile::open(&path), Err(Error::Corruption(_))));
+    assert!(matches!(
+        BfTree::open(test_util::temp_path("errors-missing.bftree"), BfTreeOptions::default()),
+        Err(Error::Io(_))
+    ));
+
+    // Offsets that would read back as a tagged pointer, and page IDs past the
+    // last segment, are refused by the mapping table
+    let memory = Arc::clone(BufferPool::new(4096).memory());
+    let mapping_table = MappingTable::new(2, Arc::clone(&memory)).unwrap();
+    assert!(matches!(mapping_table.insert(1, None, 4097), Err(Error::InvalidState(_))));
+    assert!(matches!(mapping_table.insert(1, None, 0), Err(Error::InvalidState(_))));
+    assert!(!mapping_table.contains(1));
+    assert!(matches!(mapping_table.insert(MAX_PAGE_IDS, None, 4096), Err(Error::InvalidState(_))));
+    assert!(matches!(MappingTable::new(MAX_PAGE_IDS + 1, memory), Err(Error::InvalidState(_))));
+
+    info!("[TEST] All bf_tree errors assertions passed");
+}
@@ -0,0 +1,233 @@
+use bftree::{BfTree, BfTreeOptions, BufferPool, LeafPage, MappingTable, MiniPage, RecordType, WalSyncPolicy};
+use std::sync::Arc;
+use log::{info, debug};
+mod test_util;
+
+#[test]
+fn test_buffer_pool_ring() {
+    info!("[TEST] buffer_pool ring allocation");
+
+    let mut pool = BufferPool::new(4096);
+
+    // Fill the ring: 2048 + 1024 + 512 = 3584 bytes
+    let a = pool.allocate(1, 2048).unwrap();
+    let b = pool.allocate(2, 1024).unwrap();
+    let c = pool.allocate(3, 512).unwrap();
+    assert_eq!((a, b, c), (0, 2048, 3072));
+    assert_eq!(pool.used_bytes(), 3584);
+
+    // 1024 bytes do not fit → caller must evict from the tail
+    assert!(!pool.can_allocate(1024));
+    assert!(pool.allocate(4, 1024).is_none());
+
+    // The tail slot stays reserved until its mini-page is merged and released
+    let (victim_addr, victim_page_id) = pool.evict_tail().unwrap();
+    assert_eq!((victim_addr, victim_page_id), (a, 1));
+    assert_eq!(pool.evicting(), 1);
+    assert!(pool.allocate(4, 1024).is_none());
+    assert!(pool.release(1, a));
+    assert_eq!(pool.evicting(), 0);
+
+    // The allocation wraps around to the start; the 512-byte gap at the end is padding
+    let d = pool.allocate(4, 1024).unwrap();
+    assert_eq!(d, 0);
+    assert_eq!(pool.used_bytes(), 4096 - 2048 + 1024);
+    debug!("used after wrap = {}", pool.used_bytes());
+
+    // Releasing a mini-page in the middle only reclaims once the tail reaches it
+    assert!(pool.release(3, c));
+    assert_eq!(pool.len(), 2);
+    assert!(!pool.release(3, b));
+    assert!(pool.release(2, b));
+    assert!(!pool.release(2, b));
+    assert_eq!(pool.used_bytes(), 1024);
+    assert_eq!(pool.get(d), Some((4, 1024)));
+
+    let (_, victim_page_id) = pool.evict_tail().unwrap();
+    assert_eq!(victim_page_id, 4);
+    assert!(pool.evict_tail().is_none());
+    assert!(pool.release(4, d));
+    assert!(pool.is_empty());
+    assert!(pool.evict_tail().is_none());
+
+    info!("[TEST] All buffer_pool ring assertions passed");
+}
+
+#[test]
+fn test_buffer_pool_evicting() {
+    info!("[TEST] buffer_pool hands out each victim once");
+
+    let mut pool = BufferPool::new(4096);
+    let a = pool.allocate(1, 2048).unwrap();
+    let b = pool.allocate(2, 1024).unwrap();
+    let c = pool.allocate(3, 1024).unwrap();
+    assert!(!pool.can_allocate(512));
+
+    // Concurrent evictors get different victims, oldest first
+    assert_eq!(pool.evict_tail(), Some((a, 1)));
+    assert_eq!(pool.evict_tail(), Some((b, 2)));
+    assert_eq!(pool.evicting(), 2);
+
+    // A victim that moved to another slot in the meantime was released by its owner
+    assert!(pool.release(2, b));
+    assert_eq!(pool.evicting(), 1);
+    assert!(!pool.can_allocate(512));
+    assert!(pool.release(1, a));
+
+    // Addresses are looked up by index, also after the front slots are gone
+    assert!(pool.can_allocate(2048));
+    assert_eq!(pool.get(a), None);
+    assert_eq!(pool.get(b), None);
+    assert_eq!(pool.get(c), Some((3, 1024)));
+    assert!(pool.release(3, c));
+    assert_eq!(pool.get(c), None);
+    debug!("used after release = {}", pool.used_bytes());
+    assert!(pool.is_empty());
+
+    info!("[TEST] All buffer_pool evicting assertions passed");
+}
+
+#[test]
+fn test_buffer_pool_memory() {
+    info!("[TEST] buffer_pool stores the mini-pages");
+
+    let mut pool = BufferPool::new(4096);
+    let table = MappingTable::new(4, Arc::clone(pool.memory())).unwrap();
+    let mut mini_page = MiniPage::with_size(8192, 256);
+    assert!(mini_page.insert(b"key", b"value", RecordType::Insert));
+    let addr = pool.allocate(1, 256).unwrap();
+    table.insert(1, Some((addr, mini_page)), 8192).unwrap();
+
+    // The mapping table points into the region, where the mini-page is stored
+    assert_eq!(table.get(1), Some((Some(addr), 8192)));
+    let stored = MiniPage::from_bytes(unsafe { pool.memory().read(addr, 256) });
+    assert_eq!(stored.lookup(b"key"), Some((RecordType::Insert, b"value".to_vec())));
+    assert_eq!(stored.page.node_meta.leaf, 8192);
+
+    // Changes made under the latch are written back when it is released
+    {
+        let mut entry = table.lock(1).unwrap();
+        let (_, mini_page) = entry.mini_page.as_mut().unwrap();
+        assert!(mini_page.insert(b"other", b"value", RecordType::Tombstone));
+    }
+    let stored = MiniPage::from_bytes(unsafe { pool.memory().read(addr, 256) });
+    assert_eq!(stored.lookup(b"other"), Some((RecordType::Tombstone, b"value".to_vec())));
+    debug!("stored {} records", stored.page.kv_metas.len());
+
+    // A mini-page that grows moves to a bigger slot
+    let bigger = pool.allocate(1, 512).unwrap();
+    {
+        let mut entry = table.lock(1).unwrap();
+        let (addr, mini_page) = entry.mini_page.as_mut().unwrap();
+        mini_page.resize(512);
+        *addr = bigger;
+    }
+    assert!(pool.release(1, addr));
+    assert_eq!(table.get(1), Some((Some(bigger), 8192)));
+    let stored = MiniPage::from_bytes(unsafe { pool.memory().read(bigger, 512) });
+    assert_eq!(stored.page.node_meta.node_size, 512);
+    assert_eq!(stored.lookup(b"key"), Some((RecordType::Insert, b"value".to_vec())));
+
+    info!("[TEST] All buffer_pool memory assertions passed");
+}
+
+#[test]
+fn test_buffer_pool_memory_bound() {
+    info!("[TEST] buffer_pool memory bound under inserts");
+
+    let options = BfTreeOptions::builder().buffer_pool_size(4096).wal_sync_policy(WalSyncPolicy::None).build().unwrap();
+    let tree = BfTree::create(test_util::temp_path("buffer_pool.bftree"), options).unwrap();
+    let key_of = |i: u8| vec![i, b'k'];
+    let value_of = |i: u8| format!("value-{:03}", i).into_bytes();
+
+    // Filler records spread over the key space split it into several leaves
+    for i in (0..=255u8).step_by(4) {
+        tree.insert(&[i, b'f'], &[i; 200]).unwrap();
+    }
+    tree.run_eviction(0).unwrap();
+    let leaves = tree.mapping_table().entries().count();
+    debug!("leaves = {}", leaves);
+    assert!(leaves >= 4, "expected at least 4 leaves, found {}", leaves);
+
+    // Interleave inserts over all leaves so mini-pages compete for the pool
+    for i in 0..=255u8 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+        let pool = tree.buffer_pool().lock().unwrap();
+        assert!(pool.used_bytes() <= pool.capacity());
+    }
+    let pool = tree.buffer_pool().lock().unwrap();
+    debug!("mini-pages cached = {}, used = {}", pool.len(), pool.used_bytes());
+    drop(pool);
+
+    // Evicted mini-pages were merged, so every key is still visible
+    for i in 0..=255u8 {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+    }
+
+    // A delete buffered after eviction still shadows the leaf record
+    tree.delete(&key_of(7)).unwrap();
+    assert!(tree.get(&key_of(7)).unwrap().is_none());
+
+    info!("[TEST] All buffer_pool memory bound assertions passed");
+}
+
+#[test]
+fn test_second_chance_eviction() {
+    info!("[TEST] second-chance eviction");
+
+    let options = BfTreeOptions::builder()
+        .buffer_pool_size(4096)
+        .cache_probability(0.0)
+        .wal_sync_policy(WalSyncPolicy::None)
+        .build()
+        .unwrap();
+    let tree = BfTree::create(test_util::temp_path("second_chance.bftree"), options).unwrap();
+
+    // Filler records split the key space, so "a" and "b" (hot page) and "x"
+    // (cold page) live in different leaves
+    for c in b'c'..=b'w' {
+        tree.insert(&[c], &[c; 400]).unwrap();
+    }
+    tree.run_eviction(0).unwrap();
+    let (_, hot_offset, hot_page) = tree.traverse(b"a").unwrap();
+    let (_, cold_offset, cold_page) = tree.traverse(b"x").unwrap();
+    assert_eq!(tree.traverse(b"b").unwrap().2, hot_page);
+    assert_ne!(hot_page, cold_page);
+
+    tree.insert(b"a", b"1").unwrap();
+    tree.insert(b"b", b"2").unwrap();
+    tree.insert(b"x", b"3").unwrap();
+
+    // Reading "a" sets its reference bit
+    assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert!(tree.mapping_table().mini_page(hot_page).unwrap().is_referenced());
+
+    // Evict down to a single minimum-size mini-page
+    tree.run_eviction(64).unwrap();
+
+    // The hot page got a second chance: only the referenced record stays, now clean
+    let hot_mini = tree.mapping_table().mini_page(hot_page).expect("hot mini-page was dropped");
+    assert_eq!(hot_mini.lookup(b"a"), Some((RecordType::Cache, b"1".to_vec())));
+    assert!(hot_mini.lookup(b"b").is_none());
+    assert!(!hot_mini.is_referenced());
+
+    // The cold page had no references and was dropped
+    assert!(tree.mapping_table().get(cold_page).unwrap().0.is_none());
+    let pool = tree.buffer_pool().lock().unwrap();
+    debug!("pool after eviction: {} mini-pages, {} bytes", pool.len(), pool.used_bytes());
+    drop(pool);
+
+    // All dirty records reached the leaves
+    let hot_leaf = LeafPage::load_from_disk(tree.storage(), hot_offset).unwrap();
+    assert_eq!(hot_leaf.binary_search(b"a"), Some(b"1".to_vec()));
+    assert_eq!(hot_leaf.binary_search(b"b"), Some(b"2".to_vec()));
+    let cold_leaf = LeafPage::load_from_disk(tree.storage(), cold_offset).unwrap();
+    assert_eq!(cold_leaf.binary_search(b"x"), Some(b"3".to_vec()));
+
+    // Without a new reference, the next pass drops the hot page too
+    tree.run_eviction(0).unwrap();
+    assert!(tree.buffer_pool().lock().unwrap().is_empty());
+    assert_eq!(tree.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+    info!("[TEST] All second-chance eviction assertions passed");
+}
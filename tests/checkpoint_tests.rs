@@ -0,0 +1,357 @@
+use bftree::{BfTree, BfTreeOptions, Checkpoint, Error, InnerNode, PageFile, WalSyncPolicy};
+use log::{info, debug};
+mod test_util;
+
+#[test]
+fn test_checkpoint_format() {
+    info!("[TEST] checkpoint::Checkpoint");
+
+    let root = InnerNode { keys: vec![b"m".to_vec()], children: vec![3, 4] };
+    let inner = InnerNode { keys: vec![b"c".to_vec(), b"f".to_vec()], children: vec![1, 2, 5] };
+    let checkpoint = Checkpoint {
+        sequence: 7,
+        wal_lsn: 42,
+        next_page_id: 9,
+        inner_nodes: vec![(0, root), (3, inner)],
+        leaves: vec![(1, 4096), (2, 8192), (4, 12288), (5, 16384)],
+        page_end: 28672,
+        free_pages: vec![20480, 24576],
+        free_page_ids: vec![6, 7, 8],
+    };
+    let bytes = checkpoint.serialize().unwrap();
+    assert_eq!(Checkpoint::deserialize(&bytes).unwrap(), checkpoint);
+    checkpoint.validate(4096, 32768).unwrap();
+
+    // A flipped bit anywhere in the body fails the checksum
+    let mut flipped = bytes.clone();
+    flipped[40] ^= 0x01;
+    assert!(matches!(Checkpoint::deserialize(&flipped), Err(Error::Corruption(_))));
+
+    // Torn and truncated checkpoints are rejected
+    let mut torn = bytes.clone();
+    let last = torn.len() - 1;
+    torn[last] ^= 0xFF;
+    assert!(matches!(Checkpoint::deserialize(&torn), Err(Error::Corruption(_))));
+    assert!(matches!(Checkpoint::deserialize(&bytes[..bytes.len() / 2]), Err(Error::Corruption(_))));
+
+    // Page IDs and offsets that would not build the tree it describes
+    let invalid = |f: &dyn Fn(&mut Checkpoint)| {
+        let mut invalid = checkpoint.clone();
+        f(&mut invalid);
+        matches!(invalid.validate(4096, 32768), Err(Error::Corruption(_)))
+    };
+    assert!(invalid(&|c| c.next_page_id = 1 << 45));
+    assert!(invalid(&|c| c.free_page_ids = vec![6, 7, 9]));
+    assert!(invalid(&|c| c.free_page_ids = vec![6, 7, 5]));
+    assert!(invalid(&|c| c.inner_nodes[1].1.children[2] = 6));
+    assert!(invalid(&|c| c.inner_nodes[0].1.children[1] = 1));
+    assert!(invalid(&|c| c.leaves[0].1 = 0));
+    assert!(invalid(&|c| c.leaves[0].1 = 4097));
+    assert!(invalid(&|c| c.leaves[0].1 = 28672));
+    assert!(invalid(&|c| c.free_pages[0] = 1 << 40));
+    assert!(invalid(&|c| c.page_end = 36864));
+
+    // Inner nodes must have one more child than keys, and sorted keys
+    let mut buf = Vec::new();
+    InnerNode { keys: vec![b"b".to_vec(), b"a".to_vec()], children: vec![1, 2, 3] }.serialize_into(&mut buf).unwrap();
+    assert!(matches!(InnerNode::deserialize(&buf), Err(Error::Corruption(_))));
+    buf.clear();
+    InnerNode { keys: vec![b"a".to_vec()], children: vec![1] }.serialize_into(&mut buf).unwrap();
+    assert!(matches!(InnerNode::deserialize(&buf), Err(Error::Corruption(_))));
+
+    info!("[TEST] All checkpoint::Checkpoint assertions passed");
+}
+
+#[test]
+fn test_checkpoint_restores_structure() {
+    info!("[TEST] bf_tree checkpoint restore");
+
+    let path = test_util::temp_path("restore.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let value_of = |i: u32| vec![(i % 251) as u8; 300];
+
+    // Small inner nodes, so the checkpoint holds several levels
+    let options = BfTreeOptions::builder().inner_node_size(256).mini_page_max_size(512).buffer_pool_size(64 * 1024).build().unwrap();
+    let tree = BfTree::create(&path, options.clone()).unwrap();
+    for i in 0..3000 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    tree.checkpoint().unwrap();
+    let inner_nodes = tree.inner().to_nodes();
+    debug!("inner nodes = {}, next page id = {}", inner_nodes.len(), tree.page_id_allocator().next_id());
+    assert!(inner_nodes.len() >= 5);
+
+    let mappings: Vec<_> = tree.mapping_table().entries().collect();
+    let next_page_id = tree.page_id_allocator().next_id();
+    tree.close().unwrap();
+
+    let tree = BfTree::open(&path, options).unwrap();
+    assert_eq!(tree.inner().to_nodes(), inner_nodes);
+    assert_eq!(tree.mapping_table().entries().collect::<Vec<_>>(), mappings);
+    assert_eq!(tree.page_id_allocator().next_id(), next_page_id);
+    for i in 0..3000 {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+    }
+
+    info!("[TEST] All bf_tree checkpoint restore assertions passed");
+}
+
+#[test]
+fn test_crash_after_splits() {
+    info!("[TEST] bf_tree crash after splits");
+
+    let path = test_util::temp_path("crash-splits.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let value_of = |i: u32| vec![(i % 251) as u8; 200];
+
+    // Leaves split after the checkpoint; the checkpointed leaves stay intact
+    let options = BfTreeOptions::builder().buffer_pool_size(32 * 1024).build().unwrap();
+    let tree = BfTree::create(&path, options.clone()).unwrap();
+    for i in (0..2000).step_by(2) {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    tree.checkpoint().unwrap();
+    let checkpointed_leaves = tree.mapping_table().entries().count();
+    for i in (1..2000).step_by(2) {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    tree.run_eviction(0).unwrap();
+    assert!(tree.mapping_table().entries().count() > checkpointed_leaves);
+    drop(tree);
+
+    let tree = BfTree::open(&path, options).unwrap();
+    for i in 0..2000 {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+    }
+    assert_eq!(tree.iter().count(), 2000);
+
+    info!("[TEST] All bf_tree crash after splits assertions passed");
+}
+
+#[test]
+fn test_corrupt_checkpoint() {
+    info!("[TEST] bf_tree corrupt newest checkpoint");
+
+    let path = test_util::temp_path("corrupt-checkpoint.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key_of(i), b"first").unwrap();
+    }
+    tree.checkpoint().unwrap();
+
+    // Writes between the checkpoints leave the log and the older checkpoint's
+    // leaves unable to reproduce the tree
+    for i in 0..4000 {
+        tree.insert(&key_of(i), b"second").unwrap();
+    }
+    tree.checkpoint().unwrap();
+
+    // Both slots are in use; wreck the newest checkpoint
+    let newest = tree.storage().header().checkpoints_newest_first()[0];
+    let older = tree.storage().header().checkpoints_newest_first()[1];
+    assert!(newest.sequence > older.sequence);
+    tree.storage().write_page(newest.offset, &vec![0xEE; tree.storage().page_size()]).unwrap();
+    drop(tree);
+
+    // Falling back to the older checkpoint would lose writes, so open refuses
+    let result = BfTree::open(&path, BfTreeOptions::default());
+    assert!(matches!(result, Err(Error::Corruption(_))), "{:?}", result.map(|_| ()));
+
+    info!("[TEST] All bf_tree corrupt checkpoint assertions passed");
+}
+
+#[test]
+fn test_checkpoint_validated_on_open() {
+    info!("[TEST] bf_tree open validates the checkpoint");
+
+    let path = test_util::temp_path("validate-checkpoint.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+    for i in 0..2000 {
+        tree.insert(&key_of(i), b"value").unwrap();
+    }
+    tree.checkpoint().unwrap();
+    let pointer = tree.storage().header().checkpoints_newest_first()[0];
+    let checkpoint = Checkpoint::read(tree.storage(), &pointer).unwrap();
+    let page_size = tree.storage().page_size();
+    let file_len = tree.storage().file_len().unwrap();
+    debug!("leaves = {}, next page id = {}", checkpoint.leaves.len(), checkpoint.next_page_id);
+    drop(tree);
+
+    // Checkpoints with a valid checksum but a leaf past the end of the file,
+    // or a next page ID out of all proportion, are refused
+    let damaged: [&dyn Fn(&mut Checkpoint); 2] = [&|c| c.leaves[0].1 = file_len + page_size as u64, &|c| c.next_page_id = 1 << 45];
+    for damage in damaged {
+        let mut bad = checkpoint.clone();
+        damage(&mut bad);
+        let bytes = bad.serialize().unwrap();
+        assert_eq!(bytes.len() as u64, pointer.len);
+
+        let storage = PageFile::open(&path).unwrap();
+        for (i, chunk) in bytes.chunks(page_size).enumerate() {
+            let mut page = chunk.to_vec();
+            page.resize(page_size, 0);
+            storage.write_page(pointer.offset + (i * page_size) as u64, &page).unwrap();
+        }
+        drop(storage);
+
+        let result = BfTree::open(&path, BfTreeOptions::default());
+        assert!(matches!(result, Err(Error::Corruption(_))), "{:?}", result.map(|_| ()));
+    }
+
+    info!("[TEST] All bf_tree open validates the checkpoint assertions passed");
+}
+
+#[test]
+fn test_free_pages() {
+    info!("[TEST] bf_tree free pages across checkpoints");
+
+    let path = test_util::temp_path("free-pages.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    // Without random caching the page layout is the same on every run
+    let options = BfTreeOptions::builder().buffer_pool_size(64 * 1024).cache_probability(0.0).build().unwrap();
+    let tree = BfTree::create(&path, options.clone()).unwrap();
+
+    // Leaves merged away and checkpoints dropped from the header are reused,
+    // so rounds of deletes and reinserts stop growing the file. Checkpoints
+    // need contiguous pages, which takes a few rounds to free up.
+    let mut file_lens = Vec::new();
+    for round in 0..24u8 {
+        for i in 0..2000 {
+            tree.insert(&key_of(i), &[round; 300]).unwrap();
+        }
+        tree.checkpoint().unwrap();
+        for i in (0..2000).filter(|i| i % 10 != 0) {
+            tree.delete(&key_of(i)).unwrap();
+        }
+        tree.checkpoint().unwrap();
+        file_lens.push(tree.storage().file_len().unwrap());
+    }
+    debug!("file lengths = {:?}", file_lens);
+    assert_eq!(file_lens[20..], [file_lens[19]; 4], "file keeps growing");
+
+    // The free pages survive a restart, and are handed out before the file grows
+    tree.checkpoint().unwrap();
+    let free_page_count = tree.storage().free_page_count();
+    assert!(free_page_count > 0);
+    drop(tree);
+    let tree = BfTree::open(&path, options.clone()).unwrap();
+    assert_eq!(tree.storage().free_page_count(), free_page_count);
+    let file_len = tree.storage().file_len().unwrap();
+    assert!(tree.storage().allocate_page() < file_len);
+
+    // Pages written after the last checkpoint are free after a crash: the
+    // replay rewrites the same records without growing the file further
+    for i in 0..6000 {
+        tree.insert(&key_of(i), &[9; 300]).unwrap();
+    }
+    tree.run_eviction(0).unwrap();
+    let crash_len = tree.storage().file_len().unwrap();
+    assert!(crash_len > file_len, "the free pages should run out");
+    drop(tree);
+    let tree = BfTree::open(&path, options).unwrap();
+    tree.run_eviction(0).unwrap();
+    assert_eq!(tree.storage().file_len().unwrap(), crash_len);
+    for i in 0..6000 {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(vec![9; 300]), "key {}", i);
+    }
+
+    info!("[TEST] All bf_tree free pages across checkpoints assertions passed");
+}
+
+#[test]
+fn test_auto_checkpoint() {
+    info!("[TEST] bf_tree checkpoints past the threshold");
+
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let threshold = 512 * 1024;
+    let options = BfTreeOptions::builder()
+        .buffer_pool_size(64 * 1024)
+        .cache_probability(0.0)
+        .copy_on_write(true)
+        .wal_sync_policy(WalSyncPolicy::None)
+        .checkpoint_threshold(threshold)
+        .build()
+        .unwrap();
+    let path = test_util::temp_path("auto-checkpoint.bftree");
+    let tree = BfTree::create(&path, options.clone()).unwrap();
+
+    // Every merge moves its leaf, so each round frees about as many pages as
+    // the tree has leaves. Without a single explicit checkpoint they are
+    // reused and the log is emptied.
+    let mut file_lens = Vec::new();
+    for round in 0..20u8 {
+        for i in 0..2000 {
+            tree.insert(&key_of(i), &[round; 300]).unwrap();
+            assert!(tree.wal().len() <= threshold as u64 + 512);
+        }
+        for i in (0..2000).filter(|i| i % 10 != 0) {
+            tree.delete(&key_of(i)).unwrap();
+        }
+        file_lens.push(tree.storage().file_len().unwrap());
+    }
+    debug!("file lengths = {:?}", file_lens);
+    assert!(tree.storage().header().checkpoints_newest_first()[0].sequence > 10);
+    // How much the first round leaves buffered depends on the pool; from then
+    // on each round would add about a tree's worth of leaves
+    assert!(file_lens[19] < file_lens[1] + file_lens[1] / 4, "file keeps growing: {:?}", file_lens);
+    drop(tree);
+
+    let tree = BfTree::open(&path, options).unwrap();
+    for i in 0..2000 {
+        let expected = if i % 10 == 0 { Some(vec![19; 300]) } else { None };
+        assert_eq!(tree.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+
+    info!("[TEST] All bf_tree checkpoints past the threshold assertions passed");
+}
+
+#[test]
+fn test_copy_on_write() {
+    info!("[TEST] bf_tree copy-on-write leaf flushes");
+
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    for copy_on_write in [true, false] {
+        let path = test_util::temp_path(&format!("copy-on-write-{}.bftree", copy_on_write));
+        let options = BfTreeOptions::builder().copy_on_write(copy_on_write).build().unwrap();
+        let tree = BfTree::create(&path, options.clone()).unwrap();
+        for i in 0..500 {
+            tree.insert(&key_of(i), b"first").unwrap();
+        }
+        tree.checkpoint().unwrap();
+        let checkpointed: Vec<_> = tree.mapping_table().entries().map(|(page_id, (_, offset))| (page_id, offset)).collect();
+        let pages: Vec<_> = checkpointed.iter().map(|&(_, offset)| tree.storage().read_page(offset).unwrap()).collect();
+
+        // Merges either move every leaf to a new page or overwrite it
+        for i in 0..500 {
+            tree.insert(&key_of(i), b"second").unwrap();
+        }
+        tree.run_eviction(0).unwrap();
+        for (&(page_id, offset), page) in checkpointed.iter().zip(&pages) {
+            let (_, new_offset) = tree.mapping_table().get(page_id).unwrap();
+            assert_eq!(new_offset != offset, copy_on_write);
+            assert_eq!(&tree.storage().read_page(offset).unwrap() == page, copy_on_write);
+        }
+
+        // Crash with every leaf written since the checkpoint torn halfway
+        for (_, (_, offset)) in tree.mapping_table().entries() {
+            let mut page = tree.storage().read_page(offset).unwrap();
+            page[2048..].fill(0xEE);
+            tree.storage().write_page(offset, &page).unwrap();
+        }
+        drop(tree);
+
+        // Only the checkpointed leaves, with the log replayed on top, hold every record
+        let tree = BfTree::open(&path, options).unwrap();
+        let scan: Vec<_> = tree.iter().collect();
+        if copy_on_write {
+            assert_eq!(scan.len(), 500);
+            assert!(scan.into_iter().all(|record| record.unwrap().1 == b"second"));
+        } else {
+            assert!(matches!(scan.last(), Some(Err(Error::Corruption(_)))));
+        }
+    }
+
+    info!("[TEST] All bf_tree copy-on-write leaf flushes assertions passed");
+}
@@ -0,0 +1,507 @@
+use bftree::{BackgroundMergeOptions, BackgroundMerger, BfTree, BfTreeOptions, BufferPool, Collector, MappingTable, MiniPage, WalSyncPolicy, MINI_PAGE_MIN_SIZE};
+use log::{info, debug};
+use std::sync::atomic::{AtomicPtr, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+mod test_util;
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn test_concurrent_operations() {
+    info!("[TEST] bf_tree concurrent operations");
+    assert_send_sync::<BfTree>();
+
+    let path = test_util::temp_path("concurrent.bftree");
+    let key_of = |i: u32| format!("key-{:06}", i).into_bytes();
+    let value_of = |i: u32| vec![(i % 251) as u8; 100 + (i % 7) as usize * 20];
+
+    // A small pool, so threads contend on evictions and splits
+    let options = BfTreeOptions::builder()
+        .buffer_pool_size(32 * 1024)
+        .wal_sync_policy(WalSyncPolicy::None)
+        .build()
+        .unwrap();
+    let tree = Arc::new(BfTree::create(&path, options.clone()).unwrap());
+
+    let threads: u32 = 4;
+    let per_thread: u32 = 1500;
+    let writers: Vec<_> = (0..threads)
+        .map(|t| {
+            let tree = Arc::clone(&tree);
+            thread::spawn(move || {
+                // Interleaved key ranges: every thread writes into every leaf
+                for n in 0..per_thread {
+                    let i = n * threads + t;
+                    tree.insert(&key_of(i), &value_of(i)).unwrap();
+                    if n.is_multiple_of(5) {
+                        tree.delete(&key_of(i)).unwrap();
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // Readers and scans run alongside the writers
+    let reader = {
+        let tree = Arc::clone(&tree);
+        thread::spawn(move || {
+            for round in 0..20 {
+                let keys: Vec<_> = tree.iter().map(|r| r.unwrap().0).collect();
+                assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "scan out of order in round {}", round);
+                for i in (0..threads * per_thread).step_by(97) {
+                    if let Some(value) = tree.get(&key_of(i)).unwrap() {
+                        assert_eq!(value, value_of(i));
+                    }
+                }
+            }
+        })
+    };
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    reader.join().unwrap();
+
+    let expected = |i: u32| if (i / threads).is_multiple_of(5) { None } else { Some(value_of(i)) };
+    for i in 0..threads * per_thread {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), expected(i), "key {}", i);
+    }
+    let live = (0..threads * per_thread).filter(|&i| expected(i).is_some()).count();
+    assert_eq!(tree.iter().count(), live);
+    debug!("inner nodes = {}, leaves = {}", tree.inner().node_count(), tree.mapping_table().entries().count());
+
+    // Checkpoints wait for in-flight writers; everything survives a reopen
+    Arc::into_inner(tree).unwrap().close().unwrap();
+    let tree = BfTree::open(&path, options).unwrap();
+    for i in 0..threads * per_thread {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), expected(i), "key {}", i);
+    }
+
+    info!("[TEST] All bf_tree concurrent operation assertions passed");
+}
+
+#[test]
+fn test_concurrent_checkpoints() {
+    info!("[TEST] bf_tree checkpoints during writes");
+
+    let path = test_util::temp_path("concurrent-checkpoint.bftree");
+    let options = BfTreeOptions::builder().buffer_pool_size(16 * 1024).build().unwrap();
+    let tree = Arc::new(BfTree::create(&path, options.clone()).unwrap());
+
+    let writers: Vec<_> = (0..3u32)
+        .map(|t| {
+            let tree = Arc::clone(&tree);
+            thread::spawn(move || {
+                for i in 0..800u32 {
+                    let key = (i * 3 + t).to_be_bytes();
+                    tree.insert(&key, &[t as u8; 64]).unwrap();
+                }
+            })
+        })
+        .collect();
+    // On-demand eviction frees leaf pages that a checkpoint may be writing out
+    let evictor = {
+        let tree = Arc::clone(&tree);
+        thread::spawn(move || {
+            for _ in 0..50 {
+                tree.run_eviction(0).unwrap();
+                thread::yield_now();
+            }
+        })
+    };
+    for _ in 0..5 {
+        tree.checkpoint().unwrap();
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    evictor.join().unwrap();
+
+    // Crash without a final checkpoint: the WAL covers what the checkpoints missed
+    drop(tree);
+    let tree = BfTree::open(&path, options).unwrap();
+    for i in 0..2400u32 {
+        assert_eq!(tree.get(&i.to_be_bytes()).unwrap(), Some(vec![(i % 3) as u8; 64]), "key {}", i);
+    }
+
+    info!("[TEST] All bf_tree checkpoints during writes assertions passed");
+}
+
+#[test]
+fn test_lookups_during_inner_splits() {
+    info!("[TEST] bf_tree lookups during inner node splits");
+
+    // Small inner nodes split (and grow new roots) often while readers route through them
+    let options = BfTreeOptions::builder().inner_node_size(256).mini_page_max_size(512).buffer_pool_size(64 * 1024).build().unwrap();
+    let tree = Arc::new(BfTree::create(test_util::temp_path("inner-splits.bftree"), options).unwrap());
+    let inserted = Arc::new(AtomicU32::new(0));
+    let count: u32 = 4000;
+    let key_of = |i: u32| format!("key-{:06}", i).into_bytes();
+    let value_of = |i: u32| vec![(i % 251) as u8; 300];
+
+    let readers: Vec<_> = (0..3u32)
+        .map(|r| {
+            let tree = Arc::clone(&tree);
+            let inserted = Arc::clone(&inserted);
+            thread::spawn(move || {
+                let mut lookups = 0;
+                loop {
+                    let done = inserted.load(Ordering::SeqCst);
+                    if done == count {
+                        return lookups;
+                    }
+                    // Every key inserted so far must be found, whatever splits are in flight
+                    for i in (r..done).step_by(37) {
+                        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+                        lookups += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for i in 0..count {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+        inserted.store(i + 1, Ordering::SeqCst);
+    }
+    for reader in readers {
+        debug!("reader did {} lookups", reader.join().unwrap());
+    }
+    assert!(tree.inner().node_count() >= 5, "inner nodes should have split");
+    assert_eq!(tree.iter().count(), count as usize);
+
+    info!("[TEST] All bf_tree lookups during inner node splits assertions passed");
+}
+
+#[test]
+fn test_mapping_table_growth() {
+    info!("[TEST] mapping_table::MappingTable concurrent growth");
+
+    // Starts with one segment; threads map pages across several more while
+    // others latch and install mini-pages on the pages already mapped
+    let pages: usize = 20_000;
+    let pool = Arc::new(Mutex::new(BufferPool::new(pages.div_ceil(3) * MINI_PAGE_MIN_SIZE)));
+    let table = Arc::new(MappingTable::new(16, Arc::clone(pool.lock().unwrap().memory())).unwrap());
+    let offset_of = |page_id: usize| (page_id as u64 + 1) * 4096;
+
+    let writers: Vec<_> = (0..4usize)
+        .map(|t| {
+            let table = Arc::clone(&table);
+            thread::spawn(move || {
+                for page_id in (t..pages).step_by(4) {
+                    table.insert(page_id, None, offset_of(page_id)).unwrap();
+                }
+            })
+        })
+        .collect();
+    let installers: Vec<_> = (0..2usize)
+        .map(|t| {
+            let table = Arc::clone(&table);
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                for page_id in (t..pages).step_by(2) {
+                    // Wait until the writer has mapped the page
+                    let mut entry = loop {
+                        if let Some(entry) = table.lock(page_id) {
+                            break entry;
+                        }
+                        thread::yield_now();
+                    };
+                    assert_eq!(entry.disk_offset, offset_of(page_id));
+                    if page_id.is_multiple_of(3) {
+                        let addr = pool.lock().unwrap().allocate(page_id, MINI_PAGE_MIN_SIZE).unwrap();
+                        entry.mini_page = Some((addr, MiniPage::new(entry.disk_offset)));
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in writers.into_iter().chain(installers) {
+        thread.join().unwrap();
+    }
+
+    let entries: Vec<_> = table.entries().collect();
+    assert_eq!(entries.len(), pages);
+    for (page_id, (mini_page_addr, disk_offset)) in entries {
+        assert_eq!(disk_offset, offset_of(page_id));
+        assert_eq!(mini_page_addr.is_some(), page_id.is_multiple_of(3));
+        if let Some(addr) = mini_page_addr {
+            assert_eq!(pool.lock().unwrap().get(addr), Some((page_id, MINI_PAGE_MIN_SIZE)));
+        }
+    }
+    assert!(!table.contains(pages));
+    assert_eq!(table.mini_page(3).unwrap().page.node_meta.leaf, offset_of(3));
+
+    info!("[TEST] All mapping_table::MappingTable concurrent growth assertions passed");
+}
+
+/// Poisons its values when dropped, so a reader that sees a reclaimed payload fails.
+struct Payload {
+    values: [u64; 8],
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Drop for Payload {
+    fn drop(&mut self) {
+        self.values = [u64::MAX; 8];
+        self.dropped.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_epoch_reclamation() {
+    info!("[TEST] epoch::Collector");
+
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let payload = |n: u64, dropped: &Arc<AtomicUsize>| Box::new(Payload { values: [n; 8], dropped: Arc::clone(dropped) });
+
+    // A pinned reader holds back everything retired after it pinned
+    let collector = Collector::new();
+    let guard = collector.pin();
+    collector.retire(payload(1, &dropped));
+    for _ in 0..4 {
+        collector.collect();
+    }
+    assert_eq!((collector.pending(), dropped.load(Ordering::SeqCst)), (1, 0));
+    drop(guard);
+    while collector.pending() > 0 {
+        collector.collect();
+    }
+    assert_eq!(dropped.load(Ordering::SeqCst), 1);
+
+    // Writers replace a shared payload while readers check it is never reclaimed under them
+    let collector = Arc::new(Collector::new());
+    let current = Arc::new(AtomicPtr::new(Box::into_raw(payload(0, &dropped))));
+    let replacements: u64 = 20_000;
+    let writers: Vec<_> = (0..2u64)
+        .map(|t| {
+            let (collector, current, dropped) = (Arc::clone(&collector), Arc::clone(&current), Arc::clone(&dropped));
+            thread::spawn(move || {
+                for n in (t..replacements).step_by(2) {
+                    let old = current.swap(Box::into_raw(payload(n + 1, &dropped)), Ordering::SeqCst);
+                    collector.retire(unsafe { Box::from_raw(old) });
+                }
+            })
+        })
+        .collect();
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let (collector, current) = (Arc::clone(&collector), Arc::clone(&current));
+            thread::spawn(move || {
+                for _ in 0..20_000 {
+                    let _guard = collector.pin();
+                    let payload = unsafe { &*current.load(Ordering::SeqCst) };
+                    let first = payload.values[0];
+                    thread::yield_now();
+                    assert!(first <= replacements);
+                    assert!(payload.values.iter().all(|&v| v == first), "payload reclaimed while pinned");
+                }
+            })
+        })
+        .collect();
+    for thread in writers.into_iter().chain(readers) {
+        thread.join().unwrap();
+    }
+
+    while collector.pending() > 0 {
+        collector.collect();
+    }
+    assert_eq!(dropped.load(Ordering::SeqCst), 1 + replacements as usize);
+    drop(unsafe { Box::from_raw(current.load(Ordering::SeqCst)) });
+
+    info!("[TEST] All epoch::Collector assertions passed");
+}
+
+#[test]
+fn test_traverse_during_evictions() {
+    info!("[TEST] bf_tree traverse during evictions and splits");
+
+    // A tiny pool evicts and reinstalls mini-pages constantly while readers
+    // resolve keys without taking page latches
+    let options = BfTreeOptions::builder()
+        .buffer_pool_size(16 * 1024)
+        .inner_node_size(256)
+        .mini_page_max_size(512)
+        .wal_sync_policy(WalSyncPolicy::None)
+        .build()
+        .unwrap();
+    let tree = Arc::new(BfTree::create(test_util::temp_path("traverse-evictions.bftree"), options).unwrap());
+    let inserted = Arc::new(AtomicU32::new(0));
+    let count: u32 = 3000;
+    let key_of = |i: u32| format!("key-{:06}", i).into_bytes();
+    let value_of = |i: u32| vec![(i % 251) as u8; 150];
+
+    let readers: Vec<_> = (0..4u32)
+        .map(|r| {
+            let tree = Arc::clone(&tree);
+            let inserted = Arc::clone(&inserted);
+            thread::spawn(move || {
+                let mut round = 0;
+                while inserted.load(Ordering::SeqCst) < count {
+                    for i in (r + round % 11..count).step_by(53) {
+                        let (_, disk_offset, page_id) = tree.traverse(&key_of(i)).unwrap();
+                        assert_ne!(disk_offset, 0);
+                        assert!(tree.mapping_table().contains(page_id));
+                    }
+                    let done = inserted.load(Ordering::SeqCst);
+                    for i in (r..done).step_by(41) {
+                        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+                    }
+                    round += 1;
+                }
+            })
+        })
+        .collect();
+
+    for i in 0..count {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+        inserted.store(i + 1, Ordering::SeqCst);
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    for i in 0..count {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i)), "key {}", i);
+    }
+
+    info!("[TEST] All bf_tree traverse during evictions and splits assertions passed");
+}
+
+#[test]
+fn test_checkpoint_during_cached_lookups() {
+    info!("[TEST] bf_tree checkpoints while lookups cache records");
+
+    // Every lookup installs a Cache record, so the pool never drains while readers run
+    let options = BfTreeOptions::builder()
+        .cache_probability(1.0)
+        .wal_sync_policy(WalSyncPolicy::None)
+        .build()
+        .unwrap();
+    let tree = Arc::new(BfTree::create(test_util::temp_path("checkpoint-lookups.bftree"), options).unwrap());
+    let key_of = |i: u32| format!("key-{:06}", i).into_bytes();
+    for i in 0..2000 {
+        tree.insert(&key_of(i), b"value").unwrap();
+    }
+    let stop = Arc::new(AtomicU32::new(0));
+
+    let readers: Vec<_> = (0..4u32)
+        .map(|r| {
+            let tree = Arc::clone(&tree);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                while stop.load(Ordering::SeqCst) == 0 {
+                    for i in (r..2000).step_by(7) {
+                        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(b"value".to_vec()), "key {}", i);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let checkpointer = {
+        let tree = Arc::clone(&tree);
+        thread::spawn(move || {
+            for round in 0..5u32 {
+                tree.insert(&key_of(round), b"value").unwrap();
+                tree.checkpoint().unwrap();
+            }
+            done_tx.send(()).unwrap();
+        })
+    };
+    let finished = done_rx.recv_timeout(Duration::from_secs(20)).is_ok();
+    stop.store(1, Ordering::SeqCst);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    checkpointer.join().unwrap();
+    assert!(finished, "checkpoints did not finish while lookups were running");
+    assert!(tree.wal().is_empty());
+
+    info!("[TEST] All bf_tree checkpoint during cached lookups assertions passed");
+}
+
+#[test]
+fn test_background_merge() {
+    info!("[TEST] bf_tree background merging of dirty mini-pages");
+
+    let options = BfTreeOptions::builder()
+        .buffer_pool_size(64 * 1024)
+        .cache_probability(0.0)
+        .wal_sync_policy(WalSyncPolicy::None)
+        .build()
+        .unwrap();
+    let tree = Arc::new(BfTree::create(test_util::temp_path("background-merge.bftree"), options).unwrap());
+    let merge_options = BackgroundMergeOptions { mini_page_watermark: 0.25, pool_watermark: 0.5, ..Default::default() };
+    let merge_cost = 2 * tree.storage().page_size();
+    let key_of = |i: u32| format!("key-{:06}", i).into_bytes();
+    let value_of = |i: u32, round: u32| vec![((i + round) % 251) as u8; 100];
+
+    // Dirty mini-pages at or above the watermark
+    let large_dirty = |tree: &BfTree| {
+        let watermark_size = (tree.options().mini_page_max_size as f64 * merge_options.mini_page_watermark) as usize;
+        let slots = tree.buffer_pool().lock().unwrap().live_slots();
+        slots
+            .into_iter()
+            .filter(|&(_, page_id, size)| {
+                size >= watermark_size && tree.mapping_table().mini_page(page_id).is_some_and(|m| m.is_dirty())
+            })
+            .count()
+    };
+
+    // Spread the keys over many leaves, then dirty a mini-page on each
+    for i in 0..1000 {
+        tree.insert(&key_of(i), &value_of(i, 0)).unwrap();
+    }
+    for i in (0..1000).step_by(3) {
+        tree.insert(&key_of(i), &value_of(i, 1)).unwrap();
+    }
+    let dirty = large_dirty(&tree);
+    assert!(dirty > 1, "expected several large dirty mini-pages, found {}", dirty);
+
+    // The budget bounds every pass
+    assert_eq!(tree.background_merge(&merge_options, 0).unwrap(), 0);
+    assert_eq!(large_dirty(&tree), dirty);
+    assert_eq!(tree.background_merge(&merge_options, merge_cost).unwrap(), merge_cost);
+    assert_eq!(large_dirty(&tree), dirty - 1);
+
+    let invalid = BackgroundMergeOptions { io_budget: 0, ..Default::default() };
+    assert!(BackgroundMerger::start(&tree, invalid).is_err());
+
+    // With the worker running, the pool settles below its watermark
+    let merger = BackgroundMerger::start(&tree, merge_options.clone()).unwrap();
+    for i in 0..1000 {
+        tree.insert(&key_of(i), &value_of(i, 2)).unwrap();
+    }
+    let target_used_bytes = (tree.buffer_pool().lock().unwrap().capacity() as f64 * merge_options.pool_watermark) as usize;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while large_dirty(&tree) > 0 || tree.buffer_pool().lock().unwrap().used_bytes() > target_used_bytes {
+        assert!(Instant::now() < deadline, "background merging did not catch up");
+        thread::sleep(Duration::from_millis(10));
+    }
+    merger.stop().unwrap();
+
+    for i in 0..1000 {
+        assert_eq!(tree.get(&key_of(i)).unwrap(), Some(value_of(i, 2)), "key {}", i);
+    }
+
+    // Writers hand mini-pages that cannot grow to the worker, which wakes up
+    // for them rather than after its interval, and wait for it
+    let slow = BackgroundMergeOptions { interval: Duration::from_secs(60), ..merge_options.clone() };
+    let merger = BackgroundMerger::start(&tree, slow).unwrap();
+    let hot_key = |j: u32| format!("key-000500-{:04}", j).into_bytes();
+    let started = Instant::now();
+    for j in 0..300 {
+        tree.insert(&hot_key(j), &value_of(j, 3)).unwrap();
+    }
+    debug!("hot inserts took {:?}", started.elapsed());
+    assert!(started.elapsed() < Duration::from_secs(30), "writers waited out the worker's interval");
+    merger.stop().unwrap();
+    for j in 0..300 {
+        assert_eq!(tree.get(&hot_key(j)).unwrap(), Some(value_of(j, 3)), "hot key {}", j);
+    }
+
+    info!("[TEST] All bf_tree background merging assertions passed");
+}
@@ -22,7 +22,7 @@ fn test_page_binary_search_correctness() {
 
         for key in &keys {
             let value = format!("val_{}", String::from_utf8_lossy(key)).into_bytes();
-            let inserted = page.insert(key, &value);
+            let inserted = page.insert(key, &value, RecordType::Insert);
             assert!(inserted, "Insertion should succeed for key {:?}", key);
             info!("Inserted key={:?} with value={:?}", key, value);
         }
@@ -65,3 +65,274 @@ fn test_page_binary_search_correctness() {
 
         info!("[TEST] Page binary_search correctness passed");
 }
+
+#[test]
+fn test_record_type_flags() {
+        info!("[TEST] page::RecordType");
+
+        for record_type in [RecordType::Insert, RecordType::Cache, RecordType::Tombstone, RecordType::Phantom] {
+                let flag: u8 = record_type.into();
+                assert_eq!(RecordType::try_from(flag).unwrap(), record_type);
+
+                let kv = KVMeta::new(1, 1, 0, flag, false, 0, 0).unwrap();
+                assert_eq!(kv.record_type(), record_type);
+        }
+
+        // Flags outside the 2-bit range are reported as corruption
+        assert!(matches!(RecordType::try_from(4), Err(Error::Corruption(_))));
+
+        info!("[TEST] RecordType flag conversions passed");
+}
+
+#[test]
+fn test_fence_keys() {
+        info!("[TEST] page::Page fence keys");
+
+        let mut leaf = LeafPage::with_size(512);
+        assert_eq!(leaf.page.fences(), Fences::default());
+        assert!(leaf.page.covers(b"anything"));
+
+        // Fences sit in front of the records and never match a lookup
+        leaf.insert(b"m", b"1");
+        assert!(leaf.page.set_fences(&Fences::new(b"k", Some(b"p"))));
+        leaf.insert(b"n", b"2");
+        leaf.insert(b"k", b"3");
+        assert_eq!(leaf.page.fence_count(), 2);
+        assert!(leaf.page.kv_metas[..2].iter().all(|kv| kv.is_fence));
+        assert_eq!(leaf.records(), vec![(b"k".to_vec(), b"3".to_vec()), (b"m".to_vec(), b"1".to_vec()), (b"n".to_vec(), b"2".to_vec())]);
+        assert!(leaf.binary_search(b"p").is_none());
+        assert!(leaf.page.covers(b"k") && leaf.page.covers(b"o"));
+        assert!(!leaf.page.covers(b"j") && !leaf.page.covers(b"p"));
+
+        // Fences survive a round trip through the page bytes
+        let reloaded = LeafPage::from_bytes(&leaf.to_bytes().unwrap()).unwrap();
+        assert_eq!(reloaded.page.fences(), Fences::new(b"k", Some(b"p")));
+        assert_eq!(reloaded.records(), leaf.records());
+
+        // An unbounded high fence is stored as an empty key
+        assert!(leaf.page.set_fences(&Fences::new(b"k", None)));
+        assert!(leaf.page.covers(b"zzz"));
+        assert!(leaf.page.set_fences(&Fences::default()));
+        assert_eq!(leaf.page.fence_count(), 0);
+
+        // Splitting hands each page its share of the fences
+        let records: Vec<_> = (0..60u8).map(|i| (vec![b'a' + i / 10, b'0' + i % 10], vec![i; 20])).collect();
+        let pages = LeafPage::split(records, &Fences::new(b"a", Some(b"x")), 512);
+        assert!(pages.len() > 1);
+        assert_eq!(pages[0].1.page.fences().low, b"a".to_vec());
+        assert_eq!(pages.last().unwrap().1.page.fences().high, Some(b"x".to_vec()));
+        for pair in pages.windows(2) {
+                assert_eq!(pair[0].1.page.fences().high, Some(pair[1].0.clone()));
+                assert_eq!(pair[1].1.page.fences().low, pair[1].0);
+        }
+
+        // Misplaced fence records are corruption
+        let mut bytes = leaf.to_bytes().unwrap();
+        let mut kv = KVMeta::deserialize(bytes[12..20].try_into().unwrap()).unwrap();
+        kv.is_fence = true;
+        bytes[12..20].copy_from_slice(&kv.serialize().unwrap());
+        assert!(matches!(LeafPage::from_bytes(&bytes), Err(Error::Corruption(_))));
+
+        info!("[TEST] Page fence keys passed");
+}
+
+#[test]
+fn test_lookahead() {
+        info!("[TEST] page::KVMeta lookahead");
+
+        assert_eq!(KVMeta::lookahead_of(b""), 0);
+        assert_eq!(KVMeta::lookahead_of(b"a"), 0x6100);
+        assert_eq!(KVMeta::lookahead_of(b"ab"), 0x6162);
+        assert_eq!(KVMeta::lookahead_of(b"abc"), 0x6162);
+
+        // Short keys, keys padded with zero bytes and shared prefixes all order correctly
+        let keys: Vec<&[u8]> = vec![b"", b"\0", b"\0\0", b"a", b"a\0", b"a\0\x01", b"ab", b"abc", b"abd", b"b", b"\xff\xff\xff"];
+        let mut page = Page::new(NodeMeta::new(4096, PageType::LeafPage, false, 0, 0));
+        for key in keys.iter().rev() {
+                assert!(page.insert(key, key, RecordType::Insert));
+        }
+        let stored: Vec<&[u8]> = (0..page.kv_metas.len()).map(|i| page.key_at(i)).collect();
+        assert_eq!(stored, keys);
+        for (i, key) in keys.iter().enumerate() {
+                assert_eq!(page.kv_metas[i].lookahead, KVMeta::lookahead_of(key));
+                assert_eq!(page.find_index(key), Some(i), "key {:?}", key);
+        }
+        assert_eq!(page.find_index(b"abb"), None);
+        assert_eq!(page.find_index(b"\0\0\0"), None);
+
+        // Lookaheads are serialized in full and read back as stored
+        let mut leaf = LeafPage::with_size(4096);
+        leaf.page = page;
+        let bytes = leaf.to_bytes().unwrap();
+        let kv_at = |i: usize| KVMeta::deserialize(bytes[12 + i * 8..20 + i * 8].try_into().unwrap()).unwrap();
+        assert_eq!(kv_at(6).lookahead, 0x6162);
+        assert_eq!(kv_at(10).lookahead, 0xFFFF);
+        let reloaded = LeafPage::from_bytes(&bytes).unwrap();
+        let lookaheads = |page: &Page| page.kv_metas.iter().map(|kv| kv.lookahead).collect::<Vec<_>>();
+        assert_eq!(lookaheads(&reloaded.page), lookaheads(&leaf.page));
+        for key in &keys {
+                assert_eq!(reloaded.binary_search(key), Some(key.to_vec()));
+        }
+
+        info!("[TEST] KVMeta lookahead passed");
+}
+
+#[test]
+fn test_upsert() {
+        info!("[TEST] page::Page upsert");
+
+        let mut page = Page::new(NodeMeta::new(128, PageType::MiniPage, false, 0, 0));
+        assert!(page.insert(b"key", b"value-1", RecordType::Cache));
+        assert!(page.insert(b"other", b"x", RecordType::Insert));
+
+        // A value that fits is overwritten in place; the record becomes dirty
+        let data_len = page.data.len();
+        assert!(page.insert(b"key", b"val-2", RecordType::Insert));
+        assert_eq!(page.data.len(), data_len);
+        assert_eq!(page.kv_metas.len(), 2);
+        assert_eq!(page.node_meta.record_count, 2);
+        assert_eq!(page.lookup(b"key"), Some((RecordType::Insert, b"val-2".to_vec())));
+
+        // A longer value is relocated to the end of the data block
+        assert!(page.insert(b"key", b"a longer value", RecordType::Insert));
+        assert_eq!(page.data.len(), data_len + 3 + 14);
+        assert_eq!(page.kv_metas.len(), 2);
+        assert_eq!(page.binary_search(b"key"), Some(b"a longer value".to_vec()));
+
+        // Clean records never replace dirty ones; dirty ones replace anything
+        assert!(page.insert(b"key", b"from the leaf", RecordType::Cache));
+        assert_eq!(page.lookup(b"key"), Some((RecordType::Insert, b"a longer value".to_vec())));
+        assert!(page.insert(b"key", b"", RecordType::Tombstone));
+        assert_eq!(page.lookup(b"key"), Some((RecordType::Tombstone, Vec::new())));
+        assert!(page.insert(b"key", b"", RecordType::Phantom));
+        assert_eq!(page.lookup(b"key"), Some((RecordType::Tombstone, Vec::new())));
+
+        // Without room for the relocated value the page is unchanged
+        let big = [7u8; 100];
+        assert!(!page.can_insert(b"other", &big, RecordType::Insert));
+        assert!(!page.insert(b"other", &big, RecordType::Insert));
+        assert_eq!(page.binary_search(b"other"), Some(b"x".to_vec()));
+
+        // LeafPage and MiniPage inserts are upserts too
+        let mut leaf = LeafPage::with_size(512);
+        for value in [&b"one"[..], b"two", b"three"] {
+                assert!(leaf.insert(b"k", value));
+        }
+        assert_eq!(leaf.records(), vec![(b"k".to_vec(), b"three".to_vec())]);
+        let mut mini = MiniPage::new(4096);
+        assert!(mini.insert(b"k", b"1", RecordType::Insert));
+        assert!(mini.insert(b"k", b"2", RecordType::Insert));
+        assert_eq!(mini.page.kv_metas.len(), 1);
+        assert_eq!(mini.lookup(b"k"), Some((RecordType::Insert, b"2".to_vec())));
+
+        info!("[TEST] Page upsert passed");
+}
+
+#[test]
+fn test_compact() {
+        info!("[TEST] page::Page compaction");
+
+        let mut page = Page::new(NodeMeta::new(128, PageType::MiniPage, false, 0, 0));
+        assert!(page.set_fences(&Fences::new(b"a", Some(b"z"))));
+        for key in [&b"b1"[..], b"b2", b"b3", b"b4"] {
+                assert!(page.insert(key, &[1u8; 10], RecordType::Insert));
+        }
+        assert!(page.remove(b"b2"));
+        assert!(page.insert(b"b3", &[2u8; 12], RecordType::Insert)); // relocated
+        assert_eq!(page.dead_bytes(), 12 + 12);
+
+        let records: Vec<_> = page.record_range().map(|i| (page.key_at(i).to_vec(), page.value_at(i).to_vec())).collect();
+        assert_eq!(page.compact(), 24);
+        assert_eq!(page.dead_bytes(), 0);
+        assert_eq!(page.compact(), 0);
+        let compacted: Vec<_> = page.record_range().map(|i| (page.key_at(i).to_vec(), page.value_at(i).to_vec())).collect();
+        assert_eq!(compacted, records);
+        assert_eq!(page.fences(), Fences::new(b"a", Some(b"z")));
+
+        // A record that only fits once dead space is reclaimed compacts the page
+        let mut page = Page::new(NodeMeta::new(128, PageType::MiniPage, false, 0, 0));
+        for key in [&b"k1"[..], b"k2", b"k3"] {
+                assert!(page.insert(key, &[0u8; 20], RecordType::Insert));
+        }
+        assert!(page.remove(b"k1") && page.remove(b"k2"));
+        let big = [9u8; 60];
+        assert!(page.can_insert(b"k4", &big, RecordType::Insert));
+        assert!(page.insert(b"k4", &big, RecordType::Insert));
+        assert_eq!(page.dead_bytes(), 0);
+        assert_eq!(page.binary_search(b"k3"), Some(vec![0u8; 20]));
+        assert_eq!(page.binary_search(b"k4"), Some(big.to_vec()));
+
+        // Without enough dead space the insert still fails
+        assert!(!page.can_insert(b"k5", &big, RecordType::Insert));
+        assert!(!page.insert(b"k5", &big, RecordType::Insert));
+
+        info!("[TEST] Page compaction passed");
+}
+
+#[test]
+fn test_leaf_checksum() {
+        info!("[TEST] leaf_page::LeafPage checksums and validation");
+
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c_extend(crc32c(b"1234"), b"56789"), crc32c(b"123456789"));
+
+        let mut leaf = LeafPage::with_size(512);
+        leaf.page.set_fences(&Fences::new(b"a", Some(b"m")));
+        for key in [&b"b"[..], b"d", b"f"] {
+                assert!(leaf.insert(key, b"value"));
+        }
+        let bytes = leaf.to_bytes().unwrap();
+        let loaded = LeafPage::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.records(), leaf.records());
+        assert_eq!(loaded.page.node_meta.leaf, 0);
+
+        // Any flipped bit is caught, including in the padding
+        let flipped_at = |bytes: &[u8], at: usize| {
+                let mut flipped = bytes.to_vec();
+                flipped[at] ^= 0x10;
+                flipped
+        };
+        for at in [0, 7, 40, bytes.len() - 1] {
+                assert!(matches!(LeafPage::from_bytes(&flipped_at(&bytes, at)), Err(Error::Corruption(_))), "byte {}", at);
+        }
+
+        // Clearing the checksum flag or zeroing the page does not skip verification
+        let mut unflagged = bytes.clone();
+        unflagged[2] &= !0x04;
+        assert!(matches!(LeafPage::from_bytes(&unflagged), Err(Error::Corruption(_))));
+        assert!(matches!(LeafPage::from_bytes(&vec![0; 512]), Err(Error::Corruption(_))));
+
+        // Pages with a matching checksum are still validated
+        let resealed = |edit: &dyn Fn(&mut Vec<u8>)| {
+                let mut bytes = bytes.clone();
+                edit(&mut bytes);
+                let crc = crc32c_extend(crc32c_extend(crc32c(&bytes[..6]), &[0; 4]), &bytes[10..]);
+                bytes[6..10].copy_from_slice(&crc.to_le_bytes());
+                LeafPage::from_bytes(&bytes)
+        };
+        let kv_at = |b: &[u8], i: usize| KVMeta::deserialize(b[12 + i * 8..20 + i * 8].try_into().unwrap()).unwrap();
+        assert_eq!(resealed(&|_| {}).unwrap().records(), leaf.records());
+        // A KVMeta offset past the end of the page
+        let result = resealed(&|b| {
+                let mut kv = kv_at(b, 2);
+                kv.offset = 600;
+                b[12 + 2 * 8..20 + 2 * 8].copy_from_slice(&kv.serialize().unwrap());
+        });
+        assert!(matches!(result, Err(Error::Corruption(_))));
+        // Two records swapped out of key order
+        let result = resealed(&|b| {
+                let (first, second) = (12 + 2 * 8, 12 + 3 * 8);
+                let record: Vec<u8> = b[first..second].to_vec();
+                b.copy_within(second..second + 8, first);
+                b[second..second + 8].copy_from_slice(&record);
+        });
+        assert!(matches!(result, Err(Error::Corruption(_))));
+        // A key outside the fences
+        let result = resealed(&|b| {
+                let key_start = 12 + 5 * 8 + kv_at(b, 4).offset as usize;
+                b[key_start] = b'z';
+        });
+        assert!(matches!(result, Err(Error::Corruption(_))));
+
+        info!("[TEST] LeafPage checksums passed");
+}
@@ -1,7 +1,15 @@
-use env_logger;
+use std::path::PathBuf;
 
 /// Initializes env_logger for tests automatically.
 #[ctor::ctor]
 fn init_logger() {
     let _ = env_logger::builder().is_test(true).try_init();
 }
+
+/// Returns a fresh, per-test path in the system temp directory.
+#[allow(dead_code)]
+pub fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("bftree-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
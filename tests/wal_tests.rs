@@ -0,0 +1,150 @@
+use bftree::{BfTree, BfTreeOptions, Error, RecordType, Wal, WalSyncPolicy, WAL_HEADER_SIZE, WAL_MAGIC};
+use log::{info, debug};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::Duration;
+mod test_util;
+
+#[test]
+fn test_wal_append_replay() {
+    info!("[TEST] wal::Wal");
+
+    let path = test_util::temp_path("append.wal");
+    let wal = Wal::create(&path, WalSyncPolicy::PerOp).unwrap();
+    assert!(wal.is_empty());
+    assert_eq!(wal.append(RecordType::Insert, b"a", b"1").unwrap(), 1);
+    assert_eq!(wal.append(RecordType::Tombstone, b"b", &[]).unwrap(), 2);
+    drop(wal);
+
+    let (wal, records) = Wal::open(&path, WalSyncPolicy::PerOp).unwrap();
+    debug!("replayed = {:?}", records);
+    assert_eq!(records.len(), 2);
+    assert_eq!((records[0].lsn, records[0].record_type), (1, RecordType::Insert));
+    assert_eq!((records[0].key.as_slice(), records[0].value.as_slice()), (&b"a"[..], &b"1"[..]));
+    assert_eq!((records[1].lsn, records[1].record_type), (2, RecordType::Tombstone));
+    assert_eq!(wal.next_lsn(), 3);
+
+    // A checkpoint empties the log; LSNs keep counting
+    wal.checkpoint().unwrap();
+    assert_eq!(wal.checkpoint_lsn(), 2);
+    assert_eq!(wal.len(), WAL_HEADER_SIZE);
+    assert_eq!(wal.append(RecordType::Insert, b"c", b"3").unwrap(), 3);
+    drop(wal);
+
+    // A torn record at the tail is dropped, earlier records survive
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(&[4, 0, 0, 0, 0, 0, 0, 0, 0, 9]).unwrap();
+    let (wal, records) = Wal::open(&path, WalSyncPolicy::PerOp).unwrap();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].key, b"c".to_vec());
+    assert_eq!(wal.next_lsn(), 4);
+    assert_eq!(wal.len(), WAL_HEADER_SIZE + 17 + 2);
+
+    info!("[TEST] All wal::Wal assertions passed");
+}
+
+#[test]
+fn test_wal_checksums() {
+    info!("[TEST] wal::Wal record checksums");
+
+    let path = test_util::temp_path("checksums.wal");
+    let wal = Wal::create(&path, WalSyncPolicy::PerOp).unwrap();
+    for key in [b"a", b"b", b"c"] {
+        wal.append(RecordType::Insert, key, b"value").unwrap();
+    }
+    drop(wal);
+    let log = std::fs::read(&path).unwrap();
+    let record_len = 17 + 1 + 5;
+    let value_byte = |record: usize| WAL_HEADER_SIZE as usize + record * record_len + 17 + 1;
+
+    // A damaged record followed by valid ones is corruption, not a torn tail
+    let mut damaged = log.clone();
+    damaged[value_byte(1)] ^= 1;
+    std::fs::write(&path, &damaged).unwrap();
+    let result = Wal::open(&path, WalSyncPolicy::PerOp);
+    assert!(matches!(result, Err(Error::Corruption(_))), "{:?}", result.map(|(_, records)| records));
+
+    // A damaged last record is a torn write and is dropped
+    let mut damaged = log.clone();
+    damaged[value_byte(2)] ^= 1;
+    std::fs::write(&path, &damaged).unwrap();
+    let (wal, records) = Wal::open(&path, WalSyncPolicy::PerOp).unwrap();
+    assert_eq!(records.iter().map(|r| r.key.clone()).collect::<Vec<_>>(), vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(wal.len(), WAL_HEADER_SIZE + 2 * record_len as u64);
+    drop(wal);
+
+    // A log with another magic is not a bftree WAL
+    let mut foreign = log.clone();
+    foreign[7] = 1;
+    std::fs::write(&path, &foreign).unwrap();
+    assert!(matches!(Wal::open(&path, WalSyncPolicy::PerOp), Err(Error::Corruption(_))));
+    assert_eq!(log[..8], WAL_MAGIC);
+
+    info!("[TEST] All wal::Wal checksum assertions passed");
+}
+
+#[test]
+fn test_crash_recovery() {
+    info!("[TEST] bf_tree crash recovery");
+
+    let path = test_util::temp_path("recovery.bftree");
+    let key_of = |i: u32| format!("key-{:05}", i).into_bytes();
+    let value_of = |i: u32| format!("value-{}", i).into_bytes();
+
+    // Checkpointed writes are in the page file, later ones only in the WAL
+    let tree = BfTree::create(&path, BfTreeOptions::default()).unwrap();
+    for i in 0..1000 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    tree.checkpoint().unwrap();
+    for i in 1000..1500 {
+        tree.insert(&key_of(i), &value_of(i)).unwrap();
+    }
+    for i in (0..1500).step_by(3) {
+        tree.delete(&key_of(i)).unwrap();
+    }
+    tree.insert(&key_of(0), b"revived").unwrap();
+
+    // Crash: the buffered mini-pages are lost without a checkpoint
+    drop(tree);
+
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    for i in 1..1500 {
+        let expected = if i % 3 == 0 { None } else { Some(value_of(i)) };
+        assert_eq!(tree.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+    assert_eq!(tree.get(&key_of(0)).unwrap(), Some(b"revived".to_vec()));
+
+    // Replayed records are still logged, so a second crash loses nothing either
+    drop(tree);
+    let tree = BfTree::open(&path, BfTreeOptions::default()).unwrap();
+    assert_eq!(tree.iter().count(), 1001);
+    tree.close().unwrap();
+    assert_eq!(std::fs::metadata(Wal::path_for(&path)).unwrap().len(), WAL_HEADER_SIZE);
+
+    info!("[TEST] All bf_tree crash recovery assertions passed");
+}
+
+#[test]
+fn test_sync_policies() {
+    info!("[TEST] wal sync policies");
+
+    let group_commit = WalSyncPolicy::GroupCommit { max_batch: 64, max_delay: Duration::from_millis(5) };
+    for (name, policy) in [("none", WalSyncPolicy::None), ("group", group_commit)] {
+        let path = test_util::temp_path(&format!("sync-{}.bftree", name));
+        let options = BfTreeOptions::builder().wal_sync_policy(policy).build().unwrap();
+        let tree = BfTree::create(&path, options.clone()).unwrap();
+        for i in 0..500u32 {
+            tree.insert(&i.to_be_bytes(), b"v").unwrap();
+        }
+        tree.sync_wal().unwrap();
+        drop(tree);
+
+        let tree = BfTree::open(&path, options).unwrap();
+        assert_eq!(tree.iter().count(), 500, "policy {:?}", policy);
+    }
+
+    let zero_batch = WalSyncPolicy::GroupCommit { max_batch: 0, max_delay: Duration::from_millis(5) };
+    assert!(BfTreeOptions::builder().wal_sync_policy(zero_batch).build().is_err());
+
+    info!("[TEST] All wal sync policy assertions passed");
+}